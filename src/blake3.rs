@@ -0,0 +1,269 @@
+//! The BLAKE3 hash of a whole input, for checksums, with the `blake3`
+//! feature. This follows the portable reference implementation, without
+//! its incremental hashing, keyed modes, or extended output.
+
+const IV: [u32; 8] = [
+    0x6A09_E667,
+    0xBB67_AE85,
+    0x3C6E_F372,
+    0xA54F_F53A,
+    0x510E_527F,
+    0x9B05_688C,
+    0x1F83_D9AB,
+    0x5BE0_CD19,
+];
+
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+
+/// The 32-byte hash of `data`.
+pub(crate) fn hash(data: &[u8]) -> [u8; 32] {
+    let chunks: Vec<&[u8]> = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(CHUNK_LEN).collect()
+    };
+    let last = chunks.len() - 1;
+    // The chaining values of complete subtrees, merged as each chunk
+    // completes one.
+    let mut stack: Vec<[u32; 8]> = Vec::new();
+    for (i, chunk) in chunks[..last].iter().enumerate() {
+        let mut cv = chunk_output(chunk, i as u64).chaining_value();
+        let mut total = i as u64 + 1;
+        while total & 1 == 0 {
+            cv = parent_output(stack.pop().unwrap(), cv).chaining_value();
+            total >>= 1;
+        }
+        stack.push(cv);
+    }
+    let mut output = chunk_output(chunks[last], last as u64);
+    while let Some(left) = stack.pop() {
+        output = parent_output(left, output.chaining_value());
+    }
+    output.root()
+}
+
+/// What compressing a chunk's last block or a parent node gives, before it
+/// is known whether it is the root.
+struct Output {
+    cv: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8(compress(
+            &self.cv,
+            &self.block,
+            self.counter,
+            self.block_len,
+            self.flags,
+        ))
+    }
+
+    fn root(&self) -> [u8; 32] {
+        let words = compress(&self.cv, &self.block, 0, self.block_len, self.flags | ROOT);
+        let mut out = [0; 32];
+        for (bytes, word) in out.chunks_mut(4).zip(&words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+}
+
+fn chunk_output(chunk: &[u8], counter: u64) -> Output {
+    let blocks: Vec<&[u8]> = if chunk.is_empty() {
+        vec![chunk]
+    } else {
+        chunk.chunks(BLOCK_LEN).collect()
+    };
+    let last = blocks.len() - 1;
+    let mut cv = IV;
+    for (i, block) in blocks[..last].iter().enumerate() {
+        let flags = if i == 0 { CHUNK_START } else { 0 };
+        cv = first_8(compress(
+            &cv,
+            &words(block),
+            counter,
+            BLOCK_LEN as u32,
+            flags,
+        ));
+    }
+    Output {
+        cv,
+        block: words(blocks[last]),
+        counter,
+        block_len: blocks[last].len() as u32,
+        flags: if last == 0 { CHUNK_START } else { 0 } | CHUNK_END,
+    }
+}
+
+fn parent_output(left: [u32; 8], right: [u32; 8]) -> Output {
+    let mut block = [0; 16];
+    block[..8].copy_from_slice(&left);
+    block[8..].copy_from_slice(&right);
+    Output {
+        cv: IV,
+        block,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+/// A block of up to 64 bytes as little-endian words, padded with zeros.
+fn words(block: &[u8]) -> [u32; 16] {
+    let mut padded = [0; BLOCK_LEN];
+    padded[..block.len()].copy_from_slice(block);
+    let mut words = [0; 16];
+    for (word, bytes) in words.iter_mut().zip(padded.chunks(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    words
+}
+
+fn first_8(words: [u32; 16]) -> [u32; 8] {
+    let mut first = [0; 8];
+    first.copy_from_slice(&words[..8]);
+    first
+}
+
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    // The columns, then the diagonals.
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn compress(
+    cv: &[u32; 8],
+    block: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut m = *block;
+    for i in 0..7 {
+        round(&mut state, &m);
+        if i < 6 {
+            let mut permuted = [0; 16];
+            for (p, &from) in permuted.iter_mut().zip(&MSG_PERMUTATION) {
+                *p = m[from];
+            }
+            m = permuted;
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        hash(data).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn matches_the_official_vectors() {
+        // From BLAKE3's test_vectors.json, whose inputs repeat the bytes 0 to
+        // 250, covering one and several chunks and their boundaries.
+        let input: Vec<u8> = (0..3073).map(|i| (i % 251) as u8).collect();
+        for &(len, expected) in &[
+            (
+                0,
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                1,
+                "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+            ),
+            (
+                1023,
+                "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11",
+            ),
+            (
+                1024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                1025,
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+            (
+                2048,
+                "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a",
+            ),
+            (
+                2049,
+                "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030",
+            ),
+            (
+                3072,
+                "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2",
+            ),
+            (
+                3073,
+                "7124b49501012f81cc7f11ca069ec9226cecb8a2c850cfe644e327d22d3e1cd3",
+            ),
+        ] {
+            assert_eq!(hex(&input[..len]), expected, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn hashes_abc() {
+        assert_eq!(
+            hex(b"abc"),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+}
@@ -0,0 +1,409 @@
+//! A description of the objects of a database read from its catalogs, for
+//! comparing the schemas that different runs produce.
+//!
+//! Each object is described by the statement that would create it, built
+//! from the server's own `pg_get_*def` functions where there is one, so that
+//! two databases hold the same objects when their descriptions are equal.
+//! The system schemas, the members of extensions, and schemato's tracking
+//! tables are left out.
+
+use std::collections::BTreeMap;
+
+use postgres::GenericConnection;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::json;
+
+/// An object of the database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Object {
+    /// What sort of object it is, such as `table` or `index`.
+    pub kind: String,
+    /// The qualified name, with the table for the objects of a table.
+    pub name: String,
+    /// The statement creating it.
+    pub create: String,
+    /// The statement dropping it.
+    pub drop: String,
+}
+
+/// The kinds of object, in an order in which they can be created.
+const KINDS: &[&str] = &[
+    "extension",
+    "schema",
+    "type",
+    "domain",
+    "function",
+    "procedure",
+    "sequence",
+    "table",
+    "column",
+    "constraint",
+    "index",
+    "view",
+    "materialized view",
+    "trigger",
+];
+
+/// Describe the objects of the database, ordered by kind, as in [`KINDS`],
+/// and name.
+pub(crate) fn describe(conn: &dyn GenericConnection, cfg: &Config) -> Result<Vec<Object>> {
+    let schemas = if cfg.tracking_schema == "public" {
+        Vec::new()
+    } else {
+        vec![cfg.tracking_schema.clone()]
+    };
+    let tables = cfg.tracking_tables();
+    let rows = conn
+        .query(DESCRIBE, &[&schemas, &tables])
+        .map_err(|e| Error::Sql(format!("failed describing {}", cfg.db_name), e))?;
+    // Columns go in the order of their tables, as generated columns refer
+    // to those before them.
+    let mut objects: Vec<(Option<usize>, String, i32, Object)> = rows
+        .iter()
+        .map(|row| {
+            let kind: String = row.get(0);
+            let (name, table): (String, Option<String>) = (row.get(2), row.get(3));
+            let rank = KINDS.iter().position(|&k| k == kind);
+            let within = table.clone().unwrap_or_else(|| name.clone());
+            (
+                rank,
+                within,
+                row.get(1),
+                object(kind, name, table, row.get(4)),
+            )
+        })
+        .collect();
+    objects.sort_by(|a, b| (a.0, &a.1, a.2, &a.3.name).cmp(&(b.0, &b.1, b.2, &b.3.name)));
+    Ok(objects.into_iter().map(|o| o.3).collect())
+}
+
+/// Write the objects as SQL, each statement headed by comments giving the
+/// object and the statement that drops it.
+pub(crate) fn to_sql(db_name: &str, objects: &[Object]) -> String {
+    let mut sql = format!(
+        "-- The schema of {}, as written by `schemato snapshot`.\n",
+        db_name
+    );
+    for o in objects {
+        sql.push_str(&format!(
+            "\n-- {}: {}\n-- drop: {}\n{}\n",
+            o.kind, o.name, o.drop, o.create
+        ));
+    }
+    sql
+}
+
+/// Write the objects as a JSON array, an object to a line.
+pub(crate) fn to_json(objects: &[Object]) -> String {
+    let lines: Vec<String> = objects
+        .iter()
+        .map(|o| {
+            format!(
+                "  {{\"kind\": {}, \"name\": {}, \"create\": {}, \"drop\": {}}}",
+                json::string(&o.kind),
+                json::string(&o.name),
+                json::string(&o.create),
+                json::string(&o.drop)
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", lines.join(",\n"))
+}
+
+/// Read the objects back from a snapshot written by [`to_sql`] or
+/// [`to_json`].
+pub(crate) fn parse(text: &str) -> std::result::Result<Vec<Object>, String> {
+    if text.trim_start().starts_with('[') {
+        return parse_json(text);
+    }
+    let mut objects: Vec<Object> = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((n, line)) = lines.next() {
+        let header = line
+            .strip_prefix("-- ")
+            .and_then(|rest| rest.split_once(": "))
+            .filter(|(kind, _)| KINDS.contains(kind));
+        if let Some((kind, name)) = header {
+            let drop = match lines.next().and_then(|(_, l)| l.strip_prefix("-- drop: ")) {
+                Some(drop) => drop.to_string(),
+                None => return Err(format!("no drop statement after line {}", n + 1)),
+            };
+            objects.push(Object {
+                kind: kind.to_string(),
+                name: name.to_string(),
+                create: String::new(),
+                drop,
+            });
+        } else if let Some(o) = objects.last_mut() {
+            o.create.push_str(line);
+            o.create.push('\n');
+        }
+    }
+    for o in &mut objects {
+        o.create = o.create.trim_end().to_string();
+    }
+    Ok(objects)
+}
+
+fn parse_json(text: &str) -> std::result::Result<Vec<Object>, String> {
+    let items = match json::parse(text)? {
+        json::Value::Array(items) => items,
+        _ => return Err("not an array of objects".to_string()),
+    };
+    let mut objects = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let field = |key| {
+            item.get(key)
+                .and_then(json::Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| format!("object {} has no {}", i + 1, key))
+        };
+        objects.push(Object {
+            kind: field("kind")?,
+            name: field("name")?,
+            create: field("create")?,
+            drop: field("drop")?,
+        });
+    }
+    Ok(objects)
+}
+
+/// The same object in two descriptions, if it is in each.
+pub(crate) type Pair<'a> = (Option<&'a Object>, Option<&'a Object>);
+
+/// Pair the objects of two descriptions by kind and name, keeping those that
+/// are missing from either or created differently.
+pub(crate) fn compare<'a>(a: &'a [Object], b: &'a [Object]) -> Vec<Pair<'a>> {
+    let mut pairs: BTreeMap<(&str, &str), Pair> = BTreeMap::new();
+    for o in a {
+        pairs.entry((&o.kind, &o.name)).or_default().0 = Some(o);
+    }
+    for o in b {
+        pairs.entry((&o.kind, &o.name)).or_default().1 = Some(o);
+    }
+    pairs
+        .into_values()
+        .filter(|(x, y)| x.map(|o| &o.create) != y.map(|o| &o.create))
+        .collect()
+}
+
+/// The statements that change a database described by `from` into one
+/// described by `to`: the objects only in `from`, or created differently,
+/// dropped in the reverse order of their kinds, then those only in `to`, or
+/// created differently, created. Functions are replaced rather than dropped,
+/// and a column created differently is marked for review, as dropping it
+/// loses its values.
+pub(crate) fn reconcile(from: &[Object], to: &[Object]) -> Vec<String> {
+    let rank = |o: &Object| KINDS.iter().position(|&k| k == o.kind);
+    let replaced = |o: &Object| o.kind == "function" || o.kind == "procedure";
+    let pairs = compare(from, to);
+
+    let mut drops: Vec<&Object> = pairs
+        .iter()
+        .filter_map(|&(x, y)| x.filter(|o| y.is_none() || !replaced(o)))
+        .collect();
+    drops.sort_by_key(|&o| std::cmp::Reverse(rank(o)));
+    let mut creates: Vec<&Object> = pairs.iter().filter_map(|&(_, y)| y).collect();
+    creates.sort_by_key(|&o| rank(o));
+
+    let mut statements = Vec::new();
+    for o in drops {
+        if o.kind == "column" && pairs.iter().any(|&(x, y)| x == Some(o) && y.is_some()) {
+            statements.push(format!(
+                "-- Review: column {} has changed; ALTER COLUMN may keep its values.\n{}",
+                o.name, o.drop
+            ));
+        } else {
+            statements.push(o.drop.clone());
+        }
+    }
+    statements.extend(creates.into_iter().map(|o| o.create.clone()));
+    statements
+}
+
+/// Build the statements for an object from its kind, its name, the table
+/// it belongs to, and its definition as read from the catalogs.
+fn object(kind: String, name: String, table: Option<String>, def: String) -> Object {
+    let def = def.trim().trim_end_matches(';').to_string();
+    let table = table.unwrap_or_default();
+    // The objects of a table are named after it.
+    let local = |name: &str| name[table.len() + 1..].to_string();
+    let (create, drop) = match kind.as_str() {
+        "extension" => (
+            format!("CREATE EXTENSION IF NOT EXISTS {};", name),
+            format!("DROP EXTENSION {};", name),
+        ),
+        "schema" => (
+            format!("CREATE SCHEMA {};", name),
+            format!("DROP SCHEMA {};", name),
+        ),
+        "type" => (
+            format!("CREATE TYPE {} AS ENUM ({});", name, def),
+            format!("DROP TYPE {};", name),
+        ),
+        "domain" => (
+            format!("CREATE DOMAIN {} AS {};", name, def),
+            format!("DROP DOMAIN {};", name),
+        ),
+        "sequence" => (
+            format!("CREATE SEQUENCE {} {};", name, def),
+            format!("DROP SEQUENCE {};", name),
+        ),
+        "table" => (
+            format!("CREATE TABLE {} ();", name),
+            format!("DROP TABLE {};", name),
+        ),
+        "column" => (
+            format!("ALTER TABLE {} ADD COLUMN {} {};", table, local(&name), def),
+            format!("ALTER TABLE {} DROP COLUMN {};", table, local(&name)),
+        ),
+        "constraint" => (
+            format!(
+                "ALTER TABLE {} ADD CONSTRAINT {} {};",
+                table,
+                local(&name),
+                def
+            ),
+            format!("ALTER TABLE {} DROP CONSTRAINT {};", table, local(&name)),
+        ),
+        "index" => (format!("{};", def), format!("DROP INDEX {};", name)),
+        "view" => (
+            format!("CREATE VIEW {} AS\n{};", name, def),
+            format!("DROP VIEW {};", name),
+        ),
+        "materialized view" => (
+            format!("CREATE MATERIALIZED VIEW {} AS\n{};", name, def),
+            format!("DROP MATERIALIZED VIEW {};", name),
+        ),
+        "function" | "procedure" => (
+            format!("{};", def),
+            format!("DROP {} {};", kind.to_uppercase(), name),
+        ),
+        "trigger" => (
+            format!("{};", def),
+            format!("DROP TRIGGER {} ON {};", local(&name), table),
+        ),
+        _ => (format!("{};", def), String::new()),
+    };
+    Object {
+        kind,
+        name,
+        create,
+        drop,
+    }
+}
+
+/// The kind, position among its table's columns, qualified name, table, and
+/// definition of each object, given
+/// the schemas and the qualified tables to leave out.
+const DESCRIBE: &str = r#"
+    WITH ns AS (
+        SELECT n.oid, quote_ident(n.nspname) AS q
+        FROM pg_catalog.pg_namespace n
+        WHERE n.nspname NOT LIKE 'pg\_%'
+        AND n.nspname <> 'information_schema'
+        AND n.nspname <> ALL($1)
+    ), ext AS (
+        SELECT d.classid, d.objid FROM pg_catalog.pg_depend d
+        WHERE d.deptype = 'e'
+    ), rel AS (
+        SELECT c.oid, c.relname, c.relkind, ns.q || '.' || quote_ident(c.relname) AS q
+        FROM pg_catalog.pg_class c
+        JOIN ns ON ns.oid = c.relnamespace
+        JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+        WHERE n.nspname || '.' || c.relname <> ALL($2)
+        AND (c.oid NOT IN (SELECT objid FROM ext WHERE classid = 'pg_catalog.pg_class'::regclass))
+    )
+    SELECT 'extension', 0, quote_ident(e.extname), NULL, ''
+    FROM pg_catalog.pg_extension e
+    WHERE e.extname <> 'plpgsql'
+    UNION ALL
+    SELECT 'schema', 0, ns.q, NULL, ''
+    FROM ns JOIN pg_catalog.pg_namespace n ON n.oid = ns.oid
+    WHERE n.nspname <> 'public'
+    AND ns.oid NOT IN (SELECT objid FROM ext WHERE classid = 'pg_catalog.pg_namespace'::regclass)
+    UNION ALL
+    SELECT 'type', 0, ns.q || '.' || quote_ident(t.typname), NULL,
+        (SELECT string_agg(quote_literal(e.enumlabel), ', ' ORDER BY e.enumsortorder)
+         FROM pg_catalog.pg_enum e WHERE e.enumtypid = t.oid)
+    FROM pg_catalog.pg_type t JOIN ns ON ns.oid = t.typnamespace
+    WHERE t.typtype = 'e'
+    AND t.oid NOT IN (SELECT objid FROM ext WHERE classid = 'pg_catalog.pg_type'::regclass)
+    UNION ALL
+    SELECT 'domain', 0, ns.q || '.' || quote_ident(t.typname), NULL,
+        format_type(t.typbasetype, t.typtypmod)
+        || CASE WHEN t.typnotnull THEN ' NOT NULL' ELSE '' END
+        || COALESCE(' DEFAULT ' || t.typdefault, '')
+        || COALESCE((SELECT string_agg(' CONSTRAINT ' || quote_ident(k.conname) || ' '
+                || pg_get_constraintdef(k.oid), '' ORDER BY k.conname)
+            FROM pg_catalog.pg_constraint k WHERE k.contypid = t.oid), '')
+    FROM pg_catalog.pg_type t JOIN ns ON ns.oid = t.typnamespace
+    WHERE t.typtype = 'd'
+    AND t.oid NOT IN (SELECT objid FROM ext WHERE classid = 'pg_catalog.pg_type'::regclass)
+    UNION ALL
+    SELECT 'sequence', 0, rel.q, NULL,
+        'AS ' || format_type(s.seqtypid, NULL)
+        || ' INCREMENT BY ' || s.seqincrement
+        || ' MINVALUE ' || s.seqmin || ' MAXVALUE ' || s.seqmax
+        || ' START WITH ' || s.seqstart || ' CACHE ' || s.seqcache
+        || CASE WHEN s.seqcycle THEN ' CYCLE' ELSE ' NO CYCLE' END
+    FROM rel JOIN pg_catalog.pg_sequence s ON s.seqrelid = rel.oid
+    WHERE NOT EXISTS (
+        SELECT 1 FROM pg_catalog.pg_depend d
+        WHERE d.classid = 'pg_catalog.pg_class'::regclass
+        AND d.objid = rel.oid AND d.deptype = 'i'
+    )
+    UNION ALL
+    SELECT 'table', 0, rel.q, NULL, ''
+    FROM rel WHERE rel.relkind IN ('r', 'p')
+    UNION ALL
+    SELECT 'column', a.attnum::int, rel.q || '.' || quote_ident(a.attname), rel.q,
+        format_type(a.atttypid, a.atttypmod)
+        || CASE WHEN a.attnotnull THEN ' NOT NULL' ELSE '' END
+        || CASE a.attidentity
+            WHEN 'a' THEN ' GENERATED ALWAYS AS IDENTITY'
+            WHEN 'd' THEN ' GENERATED BY DEFAULT AS IDENTITY'
+            ELSE '' END
+        || COALESCE(CASE WHEN a.attgenerated = 's'
+            THEN ' GENERATED ALWAYS AS (' || pg_get_expr(ad.adbin, ad.adrelid) || ') STORED'
+            ELSE ' DEFAULT ' || pg_get_expr(ad.adbin, ad.adrelid) END, '')
+    FROM rel
+    JOIN pg_catalog.pg_attribute a ON a.attrelid = rel.oid
+    LEFT JOIN pg_catalog.pg_attrdef ad ON ad.adrelid = a.attrelid AND ad.adnum = a.attnum
+    WHERE rel.relkind IN ('r', 'p') AND a.attnum > 0 AND NOT a.attisdropped
+    UNION ALL
+    SELECT 'constraint', 0, rel.q || '.' || quote_ident(k.conname), rel.q,
+        pg_get_constraintdef(k.oid)
+    FROM rel JOIN pg_catalog.pg_constraint k ON k.conrelid = rel.oid
+    WHERE k.contype <> 'n'
+    UNION ALL
+    SELECT 'index', 0, ns.q || '.' || quote_ident(ic.relname), rel.q,
+        pg_get_indexdef(i.indexrelid)
+    FROM rel
+    JOIN pg_catalog.pg_index i ON i.indrelid = rel.oid
+    JOIN pg_catalog.pg_class ic ON ic.oid = i.indexrelid
+    JOIN ns ON ns.oid = ic.relnamespace
+    WHERE NOT EXISTS (
+        SELECT 1 FROM pg_catalog.pg_constraint k WHERE k.conindid = i.indexrelid
+        AND k.contype IN ('p', 'u', 'x')
+    )
+    UNION ALL
+    SELECT CASE rel.relkind WHEN 'v' THEN 'view' ELSE 'materialized view' END, 0,
+        rel.q, NULL, pg_get_viewdef(rel.oid, true)
+    FROM rel WHERE rel.relkind IN ('v', 'm')
+    UNION ALL
+    SELECT CASE p.prokind WHEN 'p' THEN 'procedure' ELSE 'function' END, 0,
+        ns.q || '.' || quote_ident(p.proname)
+            || '(' || pg_get_function_identity_arguments(p.oid) || ')',
+        NULL, pg_get_functiondef(p.oid)
+    FROM pg_catalog.pg_proc p JOIN ns ON ns.oid = p.pronamespace
+    WHERE p.prokind IN ('f', 'p')
+    AND p.oid NOT IN (SELECT objid FROM ext WHERE classid = 'pg_catalog.pg_proc'::regclass)
+    UNION ALL
+    SELECT 'trigger', 0, rel.q || '.' || quote_ident(g.tgname), rel.q,
+        pg_get_triggerdef(g.oid, true)
+    FROM rel JOIN pg_catalog.pg_trigger g ON g.tgrelid = rel.oid
+    WHERE NOT g.tgisinternal
+"#;
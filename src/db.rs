@@ -0,0 +1,1626 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use postgres::error::{
+    ErrorPosition, ACTIVE_SQL_TRANSACTION, DUPLICATE_DATABASE, DUPLICATE_OBJECT, INVALID_PASSWORD,
+    UNIQUE_VIOLATION,
+};
+use postgres::params::{ConnectParams, Host};
+use postgres::transaction::Transaction;
+use postgres::types::ToSql;
+use postgres::{Connection, GenericConnection, TlsMode};
+use sha2::{Digest, Sha256};
+
+use crate::config::{Access, Config, LockStrategy, TargetSessionAttrs};
+use crate::error::{Error, Result};
+use crate::events::{self, Event};
+use crate::json;
+use crate::logging;
+use crate::migrator::Fix;
+use crate::pgpass;
+use crate::prompt;
+use crate::schemata::{Directives, Migration, Repeatable};
+use crate::statements;
+use crate::tracking;
+use crate::tunnel;
+
+/// The advisory lock key: the configured one, or else one derived from the
+/// name of the database, so that migrations of different databases in a
+/// cluster do not wait for each other. Either may be negative.
+pub(crate) fn lock_key(cfg: &Config) -> i64 {
+    cfg.lock_key.unwrap_or_else(|| {
+        let digest = Sha256::digest(format!("schemato:{}", cfg.db_name).as_bytes());
+        let mut key = [0; 8];
+        key.copy_from_slice(&digest[..8]);
+        i64::from_be_bytes(key)
+    })
+}
+
+/// A row of the versions table, as reported by
+/// [`Migrator::history`](crate::Migrator::history).
+#[derive(Clone, Debug)]
+pub struct VersionRecord {
+    pub version: i64,
+    pub applied: DateTime<Utc>,
+    /// Checksum of the file when it was applied, unless applied before
+    /// checksums were recorded.
+    pub checksum: Option<String>,
+    /// File name of the schemata, unless applied before file names were
+    /// recorded.
+    pub path: Option<String>,
+    /// Description taken from the file name, if it had one.
+    pub description: Option<String>,
+    /// How long the schemata took to run.
+    pub duration: Option<Duration>,
+    /// The database user that applied it.
+    pub applied_by: Option<String>,
+    /// The host schemato ran on when it was applied.
+    pub client_host: Option<String>,
+    /// The revision of the schemata it was applied from, if known.
+    pub revision: Option<String>,
+    /// False if applying it failed, in which case it is not installed.
+    pub success: bool,
+    /// True if a later version was already installed when it was applied.
+    pub out_of_order: bool,
+    /// The labels given to the run that applied it.
+    pub labels: BTreeMap<String, String>,
+}
+
+pub(crate) fn connect_loop(cfg: &Config, anon: bool) -> Result<Connection> {
+    logging::set_database(&cfg.db_name);
+    tracking::check_names(cfg)?;
+    ident(&cfg.db_name)?;
+    let hosts = cfg.hosts()?;
+
+    let start = Instant::now();
+    let mut backoff = Duration::from_secs(cfg.backoff);
+    for attempt in 1..cfg.attempts + 1 {
+        if cfg.past_deadline() {
+            return Err(Error::Deadline(
+                "deadline passed while connecting".to_string(),
+            ));
+        }
+        let mut connected = connect_postgres(cfg, anon, &hosts);
+        if let Err((_, true)) = connected {
+            if prompt_password(cfg)? {
+                connected = connect_postgres(cfg, anon, &hosts);
+            }
+        }
+        let connected =
+            connected
+                .map_err(|(e, _)| e)
+                .and_then(|(c, params, host)| match in_recovery(&c) {
+                    Ok(standby) => Ok((standby, c, params, host)),
+                    Err(e) => Err(e.to_string()),
+                });
+        match connected {
+            Ok((false, c, params, host)) => {
+                if hosts.len() > 1 {
+                    info!("connected to {} on attempt {}", host, attempt);
+                } else {
+                    info!("connected on attempt {}", attempt);
+                }
+                watch_deadline(&c, cfg, params);
+                return Ok(c);
+            }
+            Ok((true, _, _, host)) if !cfg.wait_for_primary => {
+                return Err(Error::Connect(format!(
+                    "{} is a standby in recovery, not the primary; \
+                     give --wait-for-primary to wait for it to be promoted",
+                    host
+                )));
+            }
+            Ok((true, _, _, _)) => warn!("connected to a standby on attempt {}", attempt),
+            Err(e) => warn!("failed connection on attempt {}: {}", attempt, e),
+        }
+        if attempt == cfg.attempts {
+            break;
+        }
+        let wait = if cfg.jitter { jitter(backoff) } else { backoff };
+        if let Some(max_wait) = cfg.max_wait {
+            if start.elapsed() + wait > max_wait {
+                warn!("giving up after waiting {}s", start.elapsed().as_secs());
+                break;
+            }
+        }
+        std::thread::sleep(cfg.remaining().map_or(wait, |left| wait.min(left)));
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    Err(Error::Connect("unable to connect".to_string()))
+}
+
+/// Whether the server is a standby, replaying the primary's changes, which
+/// refuses writes.
+fn in_recovery(conn: &Connection) -> std::result::Result<bool, postgres::Error> {
+    Ok(conn.query("SELECT pg_is_in_recovery()", &[])?.get(0).get(0))
+}
+
+/// The longest to wait between connection attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A random duration between half of `backoff` and all of it.
+fn jitter(backoff: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let half = backoff / 2;
+    half + Duration::from_millis(random % (half.as_millis() as u64 + 1))
+}
+
+/// Cancel whatever the connection is running when the deadline passes. A
+/// statement that is cancelled fails, rolling back its transaction.
+fn watch_deadline(conn: &Connection, cfg: &Config, params: ConnectParams) {
+    let left = match cfg.remaining() {
+        Some(left) => left,
+        None => return,
+    };
+    let data = conn.cancel_data();
+    std::thread::spawn(move || {
+        std::thread::sleep(left);
+        // Harmless once the connection has closed.
+        let _ = postgres::cancel_query(params, TlsMode::None, &data);
+    });
+}
+
+/// The error for a failure, or for the deadline if it has passed, as when a
+/// statement was cancelled because of it.
+fn or_deadline(cfg: &Config, err: Error, doing: &str) -> Error {
+    if cfg.past_deadline() {
+        return Error::Deadline(format!("deadline passed while {}", doing));
+    }
+    err
+}
+
+/// Connect to the first of `hosts` that accepts the connection and suits
+/// `target_session_attrs`, failing with why each did not and whether any
+/// refused the password, or wanted one when none was given.
+fn connect_postgres<'a>(
+    cfg: &Config,
+    anon: bool,
+    hosts: &'a [(String, u16)],
+) -> std::result::Result<(Connection, ConnectParams, &'a str), (String, bool)> {
+    let mut failures = Vec::new();
+    let mut refused = false;
+    for (host, port) in hosts {
+        let failure = match connect_params(cfg, anon, host, *port) {
+            Ok(params) => match Connection::connect(params.clone(), TlsMode::None) {
+                Ok(c) => match suits(&c, cfg.target_session_attrs) {
+                    Ok(true) => return Ok((c, params, host)),
+                    Ok(false) => format!("not {}", cfg.target_session_attrs),
+                    Err(e) => e.to_string(),
+                },
+                Err(e) => {
+                    refused |= e.code() == Some(&INVALID_PASSWORD)
+                        || e.to_string()
+                            .contains("password was requested but not provided");
+                    e.to_string()
+                }
+            },
+            Err(e) => e.to_string(),
+        };
+        if hosts.len() > 1 {
+            failures.push(format!("{}: {}", host, failure));
+        } else {
+            failures.push(failure);
+        }
+    }
+    Err((failures.join("; "), refused))
+}
+
+/// The password given on the terminal by [`prompt_password`].
+static PROMPTED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Ask for the password on the terminal, if the configuration allows it and
+/// it has not already been asked for. Returns whether it was.
+fn prompt_password(cfg: &Config) -> Result<bool> {
+    let mut prompted = PROMPTED.lock().unwrap();
+    if !cfg.password_prompt || prompted.is_some() {
+        return Ok(false);
+    }
+    let password = prompt::password(&format!("Password for user {}: ", cfg.db_user))
+        .map_err(|e| Error::Io("failed reading the password".to_string(), e))?;
+    *prompted = Some(password);
+    Ok(true)
+}
+
+/// Whether the session suits `attrs`, as libpq's `target_session_attrs`
+/// checks it.
+fn suits(conn: &Connection, attrs: TargetSessionAttrs) -> postgres::Result<bool> {
+    let query = match attrs {
+        TargetSessionAttrs::Any => return Ok(true),
+        TargetSessionAttrs::ReadWrite => "SELECT current_setting('transaction_read_only') = 'off'",
+        TargetSessionAttrs::Primary => "SELECT NOT pg_is_in_recovery()",
+    };
+    Ok(conn.query(query, &[])?.get(0).get(0))
+}
+
+fn connect_params(cfg: &Config, anon: bool, host: &str, port: u16) -> Result<ConnectParams> {
+    let timeout = match (cfg.connect_timeout, cfg.remaining()) {
+        (Some(timeout), Some(left)) => Some(timeout.min(left)),
+        (timeout, left) => timeout.or(left),
+    };
+    // With no database named, the server takes the user's name.
+    let database = if anon { &cfg.db_user } else { &cfg.db_name };
+    let password = PROMPTED.lock().unwrap().clone();
+    let password = password.or_else(|| cfg.db_pass.clone()).or_else(|| {
+        let passfile = cfg.passfile.as_ref()?;
+        pgpass::password(passfile, host, port, database, &cfg.db_user)
+    });
+    let mut params = ConnectParams::builder();
+    params
+        .user(&cfg.db_user, password.as_deref())
+        .port(port)
+        .database(if anon { "" } else { &cfg.db_name })
+        .option("application_name", &cfg.application_name)
+        .connect_timeout(timeout);
+    if let Some(idle) = cfg.server_keepalives_idle {
+        // The server's keepalives; the client's socket is not exposed.
+        let secs = idle.as_secs().max(1).to_string();
+        params.option("tcp_keepalives_idle", &secs);
+        params.option("tcp_keepalives_interval", &secs);
+    }
+    if cfg.ssh.is_some() {
+        let local = tunnel::forward(cfg, host, port)?;
+        return Ok(params.port(local).build(Host::Tcp("127.0.0.1".to_string())));
+    }
+    Ok(params.build(if host.starts_with('/') {
+        Host::Unix(host.into())
+    } else {
+        Host::Tcp(host.to_string())
+    }))
+}
+
+/// Take the advisory lock for the session, unless it is instead taken in
+/// each transaction.
+fn lock(conn: &Connection, cfg: &Config) -> Result<()> {
+    match cfg.lock_strategy {
+        LockStrategy::Session => {
+            info!("obtaining lock");
+            acquire(conn, cfg, "pg_advisory_lock")?;
+        }
+        LockStrategy::Transaction => return Ok(()),
+        LockStrategy::Table => {
+            info!("claiming {}", cfg.lock_table());
+            claim(conn, cfg)?;
+        }
+    }
+    events::emit(Event::LockAcquired);
+    Ok(())
+}
+
+const CREATE_LOCK: &str = r#"
+    CREATE SCHEMA IF NOT EXISTS {schema};
+    CREATE TABLE IF NOT EXISTS {lock} (
+        key BIGINT PRIMARY KEY,
+        pid INTEGER,
+        locked_by TEXT,
+        locked_at TIMESTAMPTZ,
+        client_host TEXT
+    );
+"#;
+
+/// Claim the row for the lock key if no live session holds it: one whose
+/// session has ended, as when schemato exits or crashes, holds it no longer.
+const CLAIM_LOCK: &str = r#"
+    UPDATE {lock} l
+    SET pid = pg_backend_pid(),
+    locked_by = current_user,
+    locked_at = NOW(),
+    client_host = $2
+    WHERE key = $1
+    AND (l.pid IS NULL OR NOT EXISTS (
+        SELECT 1 FROM pg_catalog.pg_stat_activity a WHERE a.pid = l.pid
+    ))
+"#;
+
+/// Take the lock table's row for the lock key for this session, waiting for
+/// it as long as configured.
+fn claim(conn: &Connection, cfg: &Config) -> Result<()> {
+    let err = |e| Error::Locked(format!("failed claiming {}: {}", cfg.lock_table(), e));
+    let key = lock_key(cfg);
+    if !tracking::table_exists(conn, &cfg.lock_table()).map_err(err)? {
+        let create_lock = tracking::names(cfg, CREATE_LOCK);
+        match conn.batch_execute(&create_lock) {
+            // Created meanwhile by another migration, so that the rest now
+            // finds it exists.
+            Err(ref e) if e.code() == Some(&UNIQUE_VIOLATION) => {
+                conn.batch_execute(&create_lock).map_err(err)?
+            }
+            result => result.map_err(err)?,
+        }
+    }
+    conn.execute(
+        &tracking::names(
+            cfg,
+            "INSERT INTO {lock} (key) VALUES ($1) ON CONFLICT DO NOTHING",
+        ),
+        &[&key],
+    )
+    .map_err(err)?;
+
+    let claim_lock = tracking::names(cfg, CLAIM_LOCK);
+    let wait = if cfg.no_wait {
+        Some(Duration::from_secs(0))
+    } else {
+        cfg.lock_wait
+    };
+    let host = client_host();
+    let start = Instant::now();
+    loop {
+        if conn.execute(&claim_lock, &[&key, &host]).map_err(err)? == 1 {
+            return Ok(());
+        }
+        let waited = start.elapsed();
+        if wait.is_some_and(|wait| waited >= wait) || cfg.past_deadline() {
+            break;
+        }
+        if waited.as_secs() == 0 {
+            if let Some(holder) = claim_holder(conn, cfg, key) {
+                info!("waiting for the lock held by {}", holder);
+            }
+        }
+        let left = wait.map_or(LOCK_POLL, |wait| wait - waited);
+        let left = cfg
+            .remaining()
+            .map_or(left, |remaining| remaining.min(left));
+        std::thread::sleep(LOCK_POLL.min(left));
+    }
+    let err = Error::Locked(match claim_holder(conn, cfg, key) {
+        Some(holder) => format!("another migration is in progress: {}", holder),
+        None => "another migration is in progress".to_string(),
+    });
+    Err(or_deadline(cfg, err, "waiting for the lock"))
+}
+
+/// A description of the session holding the lock table's row for `key`.
+fn claim_holder(conn: &Connection, cfg: &Config, key: i64) -> Option<String> {
+    let query = tracking::names(
+        cfg,
+        "SELECT pid, locked_by, locked_at::text, client_host FROM {lock} WHERE key = $1",
+    );
+    let rows = conn.query(&query, &[&key]).ok()?;
+    if rows.is_empty() {
+        return None;
+    }
+    let row = rows.get(0);
+    let pid: Option<i32> = row.get(0);
+    let user: Option<String> = row.get(1);
+    let since: Option<String> = row.get(2);
+    let host: Option<String> = row.get(3);
+    Some(format!(
+        "pid {} of {} from {} since {}",
+        pid?,
+        user.unwrap_or_else(|| "unknown user".to_string()),
+        host.unwrap_or_else(|| "unknown host".to_string()),
+        since.unwrap_or_default()
+    ))
+}
+
+/// Take the advisory lock for the transaction `t`, if it is taken in each
+/// transaction.
+pub(crate) fn xact_lock(t: &dyn GenericConnection, cfg: &Config) -> Result<()> {
+    match cfg.lock_strategy {
+        LockStrategy::Session | LockStrategy::Table => Ok(()),
+        LockStrategy::Transaction => {
+            acquire(t, cfg, "pg_advisory_xact_lock")?;
+            events::emit(Event::LockAcquired);
+            Ok(())
+        }
+    }
+}
+
+/// Take the advisory lock with the function `take`, or its `pg_try_` form,
+/// waiting for it as long as configured.
+fn acquire(c: &dyn GenericConnection, cfg: &Config, take: &str) -> Result<()> {
+    let err = |e| {
+        let err = Error::Locked(format!("failed obtaining lock: {}", e));
+        or_deadline(cfg, err, "waiting for the lock")
+    };
+    let key = lock_key(cfg);
+    let wait = if cfg.no_wait {
+        Duration::from_secs(0)
+    } else if let Some(wait) = cfg.lock_wait {
+        wait
+    } else {
+        // Cancelled by the deadline's watch, if there is one.
+        debug!("obtaining lock with {}", take);
+        c.execute(&format!("SELECT {}($1)", take), &[&key])
+            .map_err(err)?;
+        return Ok(());
+    };
+
+    let try_take = format!("SELECT {}($1)", take.replace("pg_", "pg_try_"));
+    debug!("trying to obtain lock");
+    let start = Instant::now();
+    loop {
+        let rows = c.query(&try_take, &[&key]).map_err(err)?;
+        if rows.get(0).get(0) {
+            return Ok(());
+        }
+        let waited = start.elapsed();
+        if waited >= wait || cfg.past_deadline() {
+            break;
+        }
+        if waited.as_secs() == 0 {
+            info!("waiting up to {}s for the lock", wait.as_secs());
+        }
+        let left = cfg
+            .remaining()
+            .map_or(wait - waited, |left| left.min(wait - waited));
+        std::thread::sleep(LOCK_POLL.min(left));
+    }
+    let err = Error::Locked(match lock_holder(c, key) {
+        Some(holder) => format!("another migration is in progress: {}", holder),
+        None => "another migration is in progress".to_string(),
+    });
+    Err(or_deadline(cfg, err, "waiting for the lock"))
+}
+
+/// How often to try the lock while waiting a limited time for it.
+const LOCK_POLL: Duration = Duration::from_secs(1);
+
+/// The `classid` and `objid` by which `pg_locks` shows the advisory lock
+/// `key`: its high and low 32 bits.
+fn lock_ids(key: i64) -> (u32, u32) {
+    ((key >> 32) as u32, key as u32)
+}
+
+/// A description of the session holding the advisory lock `key`, if it can
+/// be seen.
+fn lock_holder(c: &dyn GenericConnection, key: i64) -> Option<String> {
+    let query = r#"
+        SELECT a.pid, a.application_name, a.usename::text, a.client_addr::text
+        FROM pg_catalog.pg_locks l
+        JOIN pg_catalog.pg_stat_activity a USING (pid)
+        WHERE l.locktype = 'advisory'
+        AND l.granted
+        AND l.database = (SELECT oid FROM pg_catalog.pg_database WHERE datname = current_database())
+        AND l.classid = $1
+        AND l.objid = $2
+        AND l.objsubid = 1
+    "#;
+    let (classid, objid) = lock_ids(key);
+    let rows = c
+        .query(query, &[&classid, &objid])
+        .map_err(|e| warn!("failed finding the holder of the lock: {}", e))
+        .ok()?;
+    if rows.is_empty() {
+        return None;
+    }
+    let row = rows.get(0);
+    let pid: i32 = row.get(0);
+    let app: Option<String> = row.get(1);
+    let user: Option<String> = row.get(2);
+    let addr: Option<String> = row.get(3);
+    Some(format!(
+        "pid {} ({}) of {} from {}",
+        pid,
+        app.filter(|a| !a.is_empty())
+            .unwrap_or_else(|| "unnamed".to_string()),
+        user.unwrap_or_else(|| "unknown user".to_string()),
+        addr.unwrap_or_else(|| "local socket".to_string())
+    ))
+}
+
+/// Connect without a database to create the target database if necessary.
+pub(crate) fn prepare_database(cfg: &Config) -> Result<()> {
+    info!("connecting to {}", cfg.uri_safe());
+    info!(
+        "making {} attempts with a backoff of {}s",
+        cfg.attempts, cfg.backoff
+    );
+    if cfg.no_create_db && cfg.roles.is_empty() {
+        return Ok(());
+    }
+
+    let anon_conn = connect_loop(cfg, true)?;
+    // Without advisory locks, concurrent creations are reconciled by
+    // create_database.
+    if cfg.lock_strategy == LockStrategy::Session {
+        lock(&anon_conn, cfg)?;
+    }
+
+    create_roles(&anon_conn, cfg)?;
+    if !cfg.no_create_db {
+        if database_exists(&anon_conn, cfg)? {
+            info!("database {} exists", cfg.db_name);
+        } else {
+            create_database(&anon_conn, cfg)?;
+        }
+    }
+
+    finish(anon_conn)
+}
+
+/// Drop the database, first ending the other sessions connected to it.
+pub(crate) fn drop_database(cfg: &Config) -> Result<()> {
+    info!("connecting to {}", cfg.uri_safe());
+    let anon_conn = connect_loop(cfg, true)?;
+    if cfg.lock_strategy == LockStrategy::Session {
+        lock(&anon_conn, cfg)?;
+    }
+    let err = |e| Error::Sql(format!("failed dropping database {}", cfg.db_name), e);
+    let terminate = "SELECT pg_terminate_backend(pid) FROM pg_catalog.pg_stat_activity \
+                     WHERE datname = $1 AND pid <> pg_backend_pid()";
+    let ended = anon_conn
+        .query(terminate, &[&cfg.db_name])
+        .map_err(err)?
+        .len();
+    if ended > 0 {
+        info!("ended {} sessions connected to {}", ended, cfg.db_name);
+    }
+    info!("dropping database {}", cfg.db_name);
+    anon_conn
+        .batch_execute(&format!("DROP DATABASE IF EXISTS {}", ident(&cfg.db_name)?))
+        .map_err(err)?;
+    finish(anon_conn)
+}
+
+/// Fail if a host matches one of the protected patterns, as before dropping
+/// anything.
+pub(crate) fn check_unprotected(cfg: &Config) -> Result<()> {
+    for (host, _) in cfg.hosts()? {
+        for protected in &cfg.protected_hosts {
+            let pattern = glob::Pattern::new(protected).map_err(|e| {
+                Error::Config(format!("bad protected host pattern {}: {}", protected, e))
+            })?;
+            if pattern.matches(&host) {
+                return Err(Error::Config(format!(
+                    "refusing to drop {} on {}, which matches the protected pattern {}",
+                    cfg.db_name, host, protected
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drop every schema the connecting role owns, the tracking schema among
+/// them, and the objects of the `public` schema, except for those belonging
+/// to extensions. Returns the number of schemas and objects dropped.
+pub(crate) fn clean(conn: &Connection, cfg: &Config) -> Result<usize> {
+    let err = |e| Error::Sql(format!("failed cleaning {}", cfg.db_name), e);
+    let t = conn.transaction().map_err(err)?;
+    xact_lock(&t, cfg)?;
+    let mut dropped = 0;
+    for row in &t.query(CLEAN_SCHEMAS, &[]).map_err(err)? {
+        let (schema, owner, owned): (String, String, bool) = (row.get(0), row.get(1), row.get(2));
+        if !owned {
+            warn!("leaving schema {}, owned by {}", schema, owner);
+            continue;
+        }
+        info!("dropping schema {}", schema);
+        t.batch_execute(&format!("DROP SCHEMA {} CASCADE", ident(&schema)?))
+            .map_err(err)?;
+        dropped += 1;
+    }
+    for row in &t.query(CLEAN_PUBLIC, &[]).map_err(err)? {
+        let (kind, name): (String, String) = (row.get(0), row.get(1));
+        debug!("dropping {} {}", kind.to_lowercase(), name);
+        // Earlier drops may have cascaded to it.
+        t.batch_execute(&format!("DROP {} IF EXISTS {} CASCADE", kind, name))
+            .map_err(err)?;
+        dropped += 1;
+    }
+    t.commit().map_err(err)?;
+    Ok(dropped)
+}
+
+/// The schemas other than the system's and `public`, excluding those of
+/// extensions, with their owners and whether the connecting role is that
+/// owner.
+const CLEAN_SCHEMAS: &str = r#"
+    SELECT n.nspname::text, pg_get_userbyid(n.nspowner)::text,
+        pg_get_userbyid(n.nspowner) = current_user
+    FROM pg_catalog.pg_namespace n
+    WHERE n.nspname NOT LIKE 'pg\_%'
+    AND n.nspname NOT IN ('information_schema', 'public')
+    AND NOT EXISTS (
+        SELECT 1 FROM pg_catalog.pg_depend d
+        WHERE d.classid = 'pg_catalog.pg_namespace'::regclass
+        AND d.objid = n.oid AND d.deptype = 'e'
+    )
+    ORDER BY n.nspname
+"#;
+
+/// The kind and qualified name of each relation, routine, and type of the
+/// `public` schema that does not belong to an extension, relations first.
+const CLEAN_PUBLIC: &str = r#"
+    SELECT kind, name FROM (
+        SELECT 1 AS o, CASE c.relkind
+            WHEN 'v' THEN 'VIEW'
+            WHEN 'm' THEN 'MATERIALIZED VIEW'
+            WHEN 'f' THEN 'FOREIGN TABLE'
+            WHEN 'S' THEN 'SEQUENCE'
+            ELSE 'TABLE' END AS kind,
+            c.oid::regclass::text AS name,
+            'pg_catalog.pg_class'::regclass AS catalog, c.oid
+        FROM pg_catalog.pg_class c
+        WHERE c.relnamespace = 'public'::regnamespace
+        AND c.relkind IN ('r', 'p', 'v', 'm', 'f', 'S')
+        UNION ALL
+        SELECT 2, CASE p.prokind
+            WHEN 'p' THEN 'PROCEDURE'
+            WHEN 'a' THEN 'AGGREGATE'
+            ELSE 'FUNCTION' END,
+            p.oid::regprocedure::text, 'pg_catalog.pg_proc'::regclass, p.oid
+        FROM pg_catalog.pg_proc p
+        WHERE p.pronamespace = 'public'::regnamespace
+        UNION ALL
+        SELECT 3, CASE t.typtype WHEN 'd' THEN 'DOMAIN' ELSE 'TYPE' END,
+            t.oid::regtype::text, 'pg_catalog.pg_type'::regclass, t.oid
+        FROM pg_catalog.pg_type t
+        WHERE t.typnamespace = 'public'::regnamespace
+        AND (t.typtype IN ('e', 'd', 'r')
+            OR t.typtype = 'c' AND t.typrelid IN (
+                SELECT oid FROM pg_catalog.pg_class WHERE relkind = 'c'))
+    ) o
+    WHERE NOT EXISTS (
+        SELECT 1 FROM pg_catalog.pg_depend d
+        WHERE d.classid = o.catalog AND d.objid = o.oid AND d.deptype = 'e'
+    )
+    ORDER BY o.o, o.name
+"#;
+
+/// Create each of the configured roles that does not exist. The passwords of
+/// those that do are left alone.
+fn create_roles(conn: &Connection, cfg: &Config) -> Result<()> {
+    for role in &cfg.roles {
+        let err = |e| Error::Sql(format!("failed creating role {}", role.name), e);
+        let exists = "SELECT 1 FROM pg_catalog.pg_roles WHERE rolname = $1";
+        if !conn.query(exists, &[&role.name]).map_err(err)?.is_empty() {
+            info!("role {} exists", role.name);
+            continue;
+        }
+        info!("creating role {}", role.name);
+        let mut create = format!("CREATE ROLE {} LOGIN", ident(&role.name)?);
+        if let Some(ref password) = role.password {
+            create += &format!(" PASSWORD {}", literal(password));
+        }
+        trace!("{}", statements::redact(&create));
+        match conn.execute(&create, &[]) {
+            // Created meanwhile, as by another migration.
+            Err(ref e)
+                if e.code() == Some(&DUPLICATE_OBJECT) || e.code() == Some(&UNIQUE_VIOLATION) =>
+            {
+                info!("role {} was created by another migration", role.name)
+            }
+            result => {
+                result.map_err(err)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn database_exists(conn: &Connection, cfg: &Config) -> Result<bool> {
+    let query_for_database = r#"
+        SELECT COUNT(*) AS c
+        FROM pg_catalog.pg_database
+        WHERE datname = $1
+    "#;
+
+    let rows = conn
+        .query(query_for_database, &[&cfg.db_name])
+        .map_err(|e| {
+            Error::Sql(
+                format!("failed to determine existence of database {}", cfg.db_name),
+                e,
+            )
+        })?;
+    let c: i64 = rows.get(0).get("c");
+    match c {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(Error::Inconsistent(format!(
+            "database {} appears {} times?",
+            cfg.db_name, c
+        ))),
+    }
+}
+
+/// Connect to the target database, take the lock, and ensure the tracking
+/// tables exist.
+pub(crate) fn connect_database(cfg: &Config) -> Result<Connection> {
+    info!("reconnecting to the {} database", cfg.db_name);
+    let conn = connect_locked(cfg)?;
+
+    let exists = tracking::exists(&conn, cfg)?;
+    tracking::upgrade(&conn, cfg, exists)?;
+    tracking::start_track(&conn, cfg)?;
+
+    Ok(conn)
+}
+
+/// Connect to the target database and take the lock, failing if the tracking
+/// tables do not exist.
+pub(crate) fn connect_existing(cfg: &Config) -> Result<Connection> {
+    let conn = connect_locked(cfg)?;
+
+    if !tracking::exists(&conn, cfg)? {
+        return Err(Error::Inconsistent(format!(
+            "{}.{} does not exist",
+            cfg.db_name,
+            cfg.versions_table()
+        )));
+    }
+    tracking::upgrade(&conn, cfg, true)?;
+
+    Ok(conn)
+}
+
+/// Connect to the target database and take the lock.
+pub(crate) fn connect_locked(cfg: &Config) -> Result<Connection> {
+    let conn = connect_loop(cfg, false)?;
+    lock(&conn, cfg)?;
+    set_session(&conn, cfg)?;
+    Ok(conn)
+}
+
+/// Apply the configured settings and timeouts to a session that holds the
+/// lock, so they do not cut short waiting for it.
+fn set_session(conn: &Connection, cfg: &Config) -> Result<()> {
+    for (name, value) in &cfg.settings {
+        debug!("setting {} to {}", name, value);
+        conn.execute("SELECT set_config($1, $2, false)", &[name, value])
+            .map_err(|e| Error::Sql(format!("failed setting {}", name), e))?;
+    }
+    let timeouts = [
+        ("statement_timeout", cfg.statement_timeout),
+        ("lock_timeout", cfg.lock_timeout),
+    ];
+    for &(name, timeout) in timeouts.iter() {
+        if let Some(timeout) = timeout {
+            debug!("setting {} to {}ms", name, timeout.as_millis());
+            conn.batch_execute(&format!("SET {} = {}", name, timeout.as_millis()))
+                .map_err(|e| Error::Sql(format!("failed setting {}", name), e))?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn finish(conn: Connection) -> Result<()> {
+    conn.finish()
+        .map_err(|e| Error::Sql("failed closing connection".to_string(), e))
+}
+
+/// The successfully applied versions.
+pub(crate) fn load_installed(
+    conn: &dyn GenericConnection,
+    cfg: &Config,
+) -> Result<BTreeMap<i64, VersionRecord>> {
+    Ok(installed(&load_history(conn, cfg)?))
+}
+
+/// The successfully applied versions of a history.
+pub(crate) fn installed(history: &[VersionRecord]) -> BTreeMap<i64, VersionRecord> {
+    history
+        .iter()
+        .filter(|r| r.success)
+        .map(|r| (r.version, r.clone()))
+        .collect()
+}
+
+/// Every row of the versions table, including failed versions.
+pub(crate) fn load_history(
+    conn: &dyn GenericConnection,
+    cfg: &Config,
+) -> Result<Vec<VersionRecord>> {
+    if cfg.track == "default" {
+        info!("loading installed versions");
+    } else {
+        info!("loading installed versions of track {}", cfg.track);
+    }
+
+    // Tables created by older versions of schemato lack some columns until
+    // they are next opened for writing.
+    let columns = tracking::columns(conn, cfg)?;
+    let mut select = vec![
+        "version::bigint AS version".to_string(),
+        "tstamp".to_string(),
+    ];
+    for &(column, missing) in tracking::OPTIONAL_COLUMNS {
+        if columns.contains(column) {
+            select.push(column.to_string());
+        } else {
+            select.push(format!("{} AS {}", missing, column));
+        }
+    }
+    // JSONB is read as text, to be parsed here.
+    select.push(if columns.contains("labels") {
+        "labels::text AS labels".to_string()
+    } else {
+        "NULL::text AS labels".to_string()
+    });
+    // Every version predating tracks belongs to the default track.
+    let track = if columns.contains("track") {
+        "track"
+    } else {
+        "'default'"
+    };
+    let query_for_installed = format!(
+        "SELECT {} FROM {} WHERE {} = $1 ORDER BY version ASC",
+        select.join(", "),
+        cfg.versions_table(),
+        track
+    );
+
+    let rows = conn
+        .query(&query_for_installed, &[&cfg.track])
+        .map_err(|e| Error::Sql("failed loading installed versions".to_string(), e))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| VersionRecord {
+            version: row.get("version"),
+            applied: row.get("tstamp"),
+            checksum: row.get("checksum"),
+            path: row.get("filename"),
+            description: row.get("description"),
+            duration: row
+                .get::<_, Option<i64>>("duration_ms")
+                .map(|ms| Duration::from_millis(ms as u64)),
+            applied_by: row.get("applied_by"),
+            client_host: row.get("client_host"),
+            revision: row.get("revision"),
+            success: row.get("success"),
+            out_of_order: row.get("out_of_order"),
+            labels: row
+                .get::<_, Option<String>>("labels")
+                .map_or_else(BTreeMap::new, |labels| parse_labels(&labels)),
+        })
+        .collect())
+}
+
+fn create_database(conn: &Connection, cfg: &Config) -> Result<()> {
+    let name = &cfg.db_name;
+    let query = "SELECT current_user::text, rolcreatedb OR rolsuper \
+                 FROM pg_catalog.pg_roles WHERE rolname = current_user";
+    let rows = conn
+        .query(query, &[])
+        .map_err(|e| Error::Sql("failed checking the CREATEDB privilege".to_string(), e))?;
+    let (user, may_create): (String, bool) = (rows.get(0).get(0), rows.get(0).get(1));
+    if !may_create {
+        return Err(Error::Config(format!(
+            "database {} does not exist, and {} may not create it; \
+             create it first and give --no-create-db",
+            name, user
+        )));
+    }
+
+    info!("creating database {}", name);
+    let mut create = format!("CREATE DATABASE {}", ident(name)?);
+    if let Some(ref owner) = cfg.db_owner {
+        create += &format!(" OWNER {}", ident(owner)?);
+    }
+    if let Some(ref template) = cfg.db_template {
+        create += &format!(" TEMPLATE {}", ident(template)?);
+    }
+    if let Some(ref encoding) = cfg.db_encoding {
+        create += &format!(" ENCODING {}", literal(encoding));
+    }
+    if let Some(ref locale) = cfg.db_locale {
+        create += &format!(" LOCALE {}", literal(locale));
+    }
+    match conn.execute(&create, &[]) {
+        // Created meanwhile by another migration not sharing the lock, which
+        // fails on the catalog's unique index if the creations overlap.
+        Err(ref e)
+            if e.code() == Some(&DUPLICATE_DATABASE) || e.code() == Some(&UNIQUE_VIOLATION) =>
+        {
+            info!("database {} was created by another migration", name);
+            Ok(())
+        }
+        Err(e) => Err(Error::Sql(format!("failed creating database {}", name), e)),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// A string literal of `s`.
+fn literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// The longest identifier Postgres keeps whole, in bytes. Longer ones are
+/// truncated.
+pub(crate) const MAX_IDENT: usize = 63;
+
+/// A quoted identifier naming `name` exactly, whatever its case or
+/// characters, failing on a name Postgres cannot hold.
+pub(crate) fn ident(name: &str) -> Result<String> {
+    let bad = |why: &str| Error::Config(format!("bad name {:?}: {}", name, why));
+    if name.is_empty() {
+        return Err(bad("it is empty"));
+    }
+    if name.contains('\0') {
+        return Err(bad("it contains a NUL character"));
+    }
+    if name.len() > MAX_IDENT {
+        return Err(bad(&format!("it is longer than {} bytes", MAX_IDENT)));
+    }
+    Ok(format!("\"{}\"", name.replace('"', "\"\"")))
+}
+
+/// Grant each configured role its access to the database and to the tables
+/// and sequences of the granted schemas, those that exist and those created
+/// later by the same role.
+pub(crate) fn grant(conn: &Connection, cfg: &Config) -> Result<()> {
+    for (name, access) in &cfg.grants {
+        info!("granting {} access to {}", access, name);
+        let (tables, sequences) = match access {
+            Access::Read => ("SELECT", "SELECT"),
+            Access::Write => ("SELECT, INSERT, UPDATE, DELETE", "USAGE, SELECT, UPDATE"),
+        };
+        let role = ident(name)?;
+        let mut sql = format!(
+            "GRANT CONNECT ON DATABASE {} TO {};\n",
+            ident(&cfg.db_name)?,
+            role
+        );
+        for schema in &cfg.grant_schemas {
+            let schema = ident(schema)?;
+            sql += &format!(
+                "GRANT USAGE ON SCHEMA {schema} TO {role};
+                GRANT {tables} ON ALL TABLES IN SCHEMA {schema} TO {role};
+                GRANT {sequences} ON ALL SEQUENCES IN SCHEMA {schema} TO {role};
+                ALTER DEFAULT PRIVILEGES IN SCHEMA {schema} GRANT {tables} ON TABLES TO {role};
+                ALTER DEFAULT PRIVILEGES IN SCHEMA {schema} GRANT {sequences} ON SEQUENCES TO {role};\n",
+                schema = schema,
+                role = role,
+                tables = tables,
+                sequences = sequences
+            );
+        }
+        conn.batch_execute(&sql)
+            .map_err(|e| Error::Sql(format!("failed granting {} access to {}", access, name), e))?;
+    }
+    Ok(())
+}
+
+/// Record a version as applied, or as failed, replacing any earlier record
+/// of a failed attempt. It is out of order if a later version of the track
+/// is already installed.
+const SET_VERSION: &str = r#"
+    INSERT INTO {versions}
+    (version, checksum, filename, duration_ms, client_host, success, track,
+     description, revision, labels, out_of_order)
+    VALUES
+    ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10::text::jsonb, COALESCE($1 < (
+        SELECT max(version) FROM {versions} WHERE track = $7 AND success
+    ), false))
+    ON CONFLICT (track, version) DO UPDATE SET
+    tstamp = NOW(),
+    checksum = EXCLUDED.checksum,
+    filename = EXCLUDED.filename,
+    description = EXCLUDED.description,
+    duration_ms = EXCLUDED.duration_ms,
+    applied_by = current_user,
+    client_host = EXCLUDED.client_host,
+    revision = EXCLUDED.revision,
+    labels = EXCLUDED.labels,
+    success = EXCLUDED.success,
+    out_of_order = EXCLUDED.out_of_order
+"#;
+
+const UNSET_VERSION: &str = r#"
+    DELETE FROM {versions}
+    WHERE version = $1
+    AND track = $2
+"#;
+
+/// Open the transaction a file runs in, unless its directives opt out of one,
+/// taking the lock in it if it is taken in each transaction.
+fn begin<'a>(
+    conn: &'a dyn GenericConnection,
+    path: &str,
+    dir: &Directives,
+    cfg: &Config,
+) -> Result<Option<Transaction<'a>>> {
+    if dir.no_transaction {
+        if cfg.lock_strategy == LockStrategy::Transaction {
+            return Err(Error::Config(format!(
+                "{}: no-transaction files cannot run under the transaction lock strategy",
+                path
+            )));
+        }
+        return Ok(None);
+    }
+    let t = conn
+        .transaction()
+        .map_err(|e| Error::Sql(format!("failed starting a transaction for {}", path), e))?;
+    xact_lock(&t, cfg)?;
+    Ok(Some(t))
+}
+
+/// Whether a query for a single boolean, run after taking the lock in a
+/// file's transaction, finds that another migration has already done the
+/// file's work. Without that lock strategy, nothing else could have.
+fn done_elsewhere(
+    c: &dyn GenericConnection,
+    cfg: &Config,
+    query: &str,
+    params: &[&dyn ToSql],
+) -> Result<bool> {
+    if cfg.lock_strategy != LockStrategy::Transaction {
+        return Ok(false);
+    }
+    let rows = c
+        .query(query, params)
+        .map_err(|e| Error::Sql("failed rereading the tracking tables".to_string(), e))?;
+    Ok(rows.get(0).get(0))
+}
+
+const LATEST: &str = r#"
+    SELECT max(version)::bigint FROM {versions} WHERE track = $1 AND success
+"#;
+
+/// The latest successfully applied version, if any.
+pub(crate) fn latest_version(conn: &dyn GenericConnection, cfg: &Config) -> Result<Option<i64>> {
+    let rows = conn
+        .query(&tracking::names(cfg, LATEST), &[&cfg.track])
+        .map_err(|e| Error::Sql("failed loading the latest version".to_string(), e))?;
+    Ok(rows.get(0).get(0))
+}
+
+/// Send `payload` to the sessions listening on `channel`, once the run's
+/// transaction, if any, commits.
+pub(crate) fn notify(conn: &dyn GenericConnection, channel: &str, payload: &str) -> Result<()> {
+    info!("notifying {}", channel);
+    conn.execute("SELECT pg_notify($1, $2)", &[&channel, &payload])
+        .map_err(|e| Error::Sql(format!("failed notifying {}", channel), e))?;
+    Ok(())
+}
+
+const RECORD_RUN: &str = r#"
+    INSERT INTO {runs}
+    (track, command, started, client_host, outcome, error, changed,
+     applied, reverted, versions, tool_version, revision, labels)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13::text::jsonb)
+"#;
+
+/// Record a run in the runs table, from its events, if the tracking tables
+/// have reached the format that has it. Returns whether it was recorded.
+pub(crate) fn record_run(
+    cfg: &Config,
+    command: &str,
+    started: DateTime<Utc>,
+    outcome: &Result<usize>,
+    events: &[Event],
+) -> Result<bool> {
+    let conn = connect_loop(cfg, false)?;
+    let err = |e| {
+        Error::Sql(
+            format!("failed recording the run in {}", cfg.runs_table()),
+            e,
+        )
+    };
+    if !tracking::table_exists(&conn, &cfg.runs_table()).map_err(err)? {
+        return Ok(false);
+    }
+    let (mut applied, mut reverted, mut versions) = (Vec::new(), Vec::new(), Vec::new());
+    for event in events {
+        match *event {
+            Event::Applied {
+                version, ref path, ..
+            } => {
+                applied.push(path.clone());
+                versions.extend(version);
+            }
+            Event::Reverted {
+                version, ref path, ..
+            } => {
+                reverted.push(path.clone());
+                versions.push(version);
+            }
+            _ => {}
+        }
+    }
+    let (result, error, changed) = match *outcome {
+        Ok(n) => ("success", None, Some(n as i64)),
+        Err(ref e) => ("failure", Some(e.to_string()), None),
+    };
+    conn.execute(
+        &tracking::names(cfg, RECORD_RUN),
+        &[
+            &cfg.track,
+            &command,
+            &started,
+            &client_host(),
+            &result,
+            &error,
+            &changed,
+            &applied,
+            &reverted,
+            &versions,
+            &env!("CARGO_PKG_VERSION"),
+            &cfg.revision,
+            &labels(cfg),
+        ],
+    )
+    .map_err(err)?;
+    Ok(true)
+}
+
+const APPLIED: &str = r#"
+    SELECT EXISTS (
+        SELECT 1 FROM {versions}
+        WHERE track = $1 AND version = $2 AND success
+    )
+"#;
+
+/// The transaction a file runs in, or else the connection itself.
+fn within<'a>(
+    conn: &'a dyn GenericConnection,
+    t: &'a Option<Transaction>,
+) -> &'a dyn GenericConnection {
+    match t {
+        Some(t) => t,
+        None => conn,
+    }
+}
+
+/// Finish running a file, committing its transaction if it had one.
+fn commit(t: Option<Transaction>) -> postgres::Result<()> {
+    t.map_or(Ok(()), Transaction::commit)
+}
+
+/// Run the SQL of the file at `path` under the statement timeout from its
+/// directives. Outside of a transaction, the timeout is restored afterwards.
+/// `doing` describes the run for errors, as in "applying version 3".
+fn execute(
+    c: &dyn GenericConnection,
+    path: &str,
+    d: &str,
+    dir: &Directives,
+    doing: &str,
+    cfg: &Config,
+) -> Result<()> {
+    let err = |e| or_deadline(cfg, Error::Sql(format!("failed {}", doing), e), doing);
+    let ms = match dir.timeout {
+        Some(timeout) => timeout.as_millis(),
+        None => return execute_statements(c, path, d, dir, doing, cfg),
+    };
+    if !dir.no_transaction {
+        c.batch_execute(&format!("SET LOCAL statement_timeout = {}", ms))
+            .map_err(err)?;
+        return execute_statements(c, path, d, dir, doing, cfg);
+    }
+    let prior: String = c
+        .query("SHOW statement_timeout", &[])
+        .map_err(err)?
+        .get(0)
+        .get(0);
+    c.batch_execute(&format!("SET statement_timeout = {}", ms))
+        .map_err(err)?;
+    let result = execute_statements(c, path, d, dir, doing, cfg);
+    let restored = c.execute(
+        "SELECT set_config('statement_timeout', $1, false)",
+        &[&prior],
+    );
+    match (result, restored) {
+        (Err(e), Err(restore)) => {
+            warn!(
+                "failed restoring statement_timeout after {}: {}",
+                doing, restore
+            );
+            Err(e)
+        }
+        (result, restored) => {
+            restored.map_err(err)?;
+            result
+        }
+    }
+}
+
+const NO_TRANSACTION_HINT: &str =
+    "HINT: start the file with `-- schemato: no-transaction` to run it outside of a transaction";
+
+/// Run each statement of a file's SQL in turn, failing with the line of the
+/// file at which the failing statement went wrong and the lines around it.
+/// Once the deadline passes no further statement is started.
+fn execute_statements(
+    c: &dyn GenericConnection,
+    path: &str,
+    d: &str,
+    dir: &Directives,
+    doing: &str,
+    cfg: &Config,
+) -> Result<()> {
+    let split = statements::split(d);
+    for (n, s) in split.iter().enumerate() {
+        if cfg.past_deadline() {
+            return Err(Error::Deadline(format!("deadline passed while {}", doing)));
+        }
+        trace!(
+            "statement {} of {}:\n{}",
+            n + 1,
+            path,
+            statements::redact(s.sql)
+        );
+        if split.len() > 1 {
+            events::emit(Event::Executing {
+                path: path.to_string(),
+                statement: n + 1,
+                statements: split.len(),
+            });
+        }
+        c.batch_execute(s.sql).map_err(|e| {
+            if cfg.past_deadline() {
+                return Error::Deadline(format!("deadline passed while {}", doing));
+            }
+            let line = match e.as_db().and_then(|db| db.position.as_ref()) {
+                Some(ErrorPosition::Normal(p)) => s.line_at(*p as usize),
+                _ => s.line,
+            };
+            let at = format!("line {} of {} (statement {})", line, path, n + 1);
+            let mut notes = s.excerpt(line);
+            // Such as CREATE INDEX CONCURRENTLY, in the wrapping transaction.
+            if !dir.no_transaction && e.code() == Some(&ACTIVE_SQL_TRANSACTION) {
+                notes = format!("{}\n{}", NO_TRANSACTION_HINT, notes);
+            }
+            Error::Statement(format!("failed {} at {}", doing, at), e, notes)
+        })?;
+    }
+    Ok(())
+}
+
+/// Apply a version's SQL and record it with the checksum of its file.
+/// Returns false if it was applied by another migration, or failed and
+/// `--force` continued through the error.
+pub(crate) fn apply(
+    conn: &dyn GenericConnection,
+    m: &Migration,
+    d: &str,
+    dir: &Directives,
+    sum: &str,
+    cfg: &Config,
+) -> Result<bool> {
+    let (ver, path) = (m.version, &m.path);
+    let description = dir.description.clone().or_else(|| m.description.clone());
+    let err = |e| Error::Sql(format!("failed applying version {}", ver), e);
+    let set_version = tracking::names(cfg, SET_VERSION);
+    let host = client_host();
+    let t = begin(conn, path, dir, cfg)?;
+    let applied = tracking::names(cfg, APPLIED);
+    if done_elsewhere(within(conn, &t), cfg, &applied, &[&cfg.track, &ver])? {
+        info!("version {} was applied by another migration", ver);
+        events::emit(Event::Skipped {
+            version: Some(ver),
+            path: path.clone(),
+            reason: "applied by another migration".to_string(),
+        });
+        return Ok(false);
+    }
+    let start = Instant::now();
+    match execute(
+        within(conn, &t),
+        path,
+        d,
+        dir,
+        &format!("applying version {}", ver),
+        cfg,
+    ) {
+        Ok(_) => {
+            let ms = start.elapsed().as_millis() as i64;
+            within(conn, &t)
+                .execute(
+                    &set_version,
+                    &[
+                        &ver,
+                        &sum,
+                        path,
+                        &ms,
+                        &host,
+                        &true,
+                        &cfg.track,
+                        &description,
+                        &cfg.revision,
+                        &labels(cfg),
+                    ],
+                )
+                .map_err(|e| Error::Sql(format!("failed registering version {}", ver), e))?;
+        }
+        Err(e) => {
+            let ms = start.elapsed().as_millis() as i64;
+            if let Some(t) = t {
+                t.set_rollback();
+            }
+            if let Err(e) = conn.execute(
+                &set_version,
+                &[
+                    &ver,
+                    &sum,
+                    path,
+                    &ms,
+                    &host,
+                    &false,
+                    &cfg.track,
+                    &description,
+                    &cfg.revision,
+                    &labels(cfg),
+                ],
+            ) {
+                warn!("failed recording the failure of version {}: {}", ver, e);
+            }
+            if cfg.force && !cfg.past_deadline() {
+                warn!("continuing through error: {}", e);
+                events::emit(Event::Failed {
+                    version: Some(ver),
+                    path: path.clone(),
+                    error: e.to_string(),
+                });
+                return Ok(false);
+            }
+            return Err(e);
+        }
+    }
+    commit(t).map_err(err)?;
+    Ok(true)
+}
+
+/// The checksum each file recorded in `table`, the repeatables or seeds
+/// table, had when last applied, by file name.
+pub(crate) fn load_repeated(
+    conn: &dyn GenericConnection,
+    cfg: &Config,
+    table: &str,
+) -> Result<BTreeMap<String, String>> {
+    let err = |e| Error::Sql(format!("failed loading {}", table), e);
+    // Absent until the tracking tables are next opened for writing.
+    if !tracking::table_exists(conn, table).map_err(err)? {
+        return Ok(BTreeMap::new());
+    }
+
+    let rows = conn
+        .query(
+            &format!("SELECT filename, checksum FROM {} WHERE track = $1", table),
+            &[&cfg.track],
+        )
+        .map_err(err)?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+const SET_REPEATED: &str = r#"
+    INSERT INTO {repeated}
+    (track, filename, checksum, duration_ms, client_host, revision)
+    VALUES
+    ($1, $2, $3, $4, $5, $6)
+    ON CONFLICT (track, filename) DO UPDATE SET
+    checksum = EXCLUDED.checksum,
+    tstamp = NOW(),
+    duration_ms = EXCLUDED.duration_ms,
+    applied_by = current_user,
+    client_host = EXCLUDED.client_host,
+    revision = EXCLUDED.revision
+"#;
+
+/// Apply a repeatable or seed file's SQL and record the checksum of the
+/// file in `table`. Returns false as [`apply`] does.
+pub(crate) fn repeat(
+    conn: &dyn GenericConnection,
+    table: &str,
+    r: &Repeatable,
+    d: &str,
+    dir: &Directives,
+    sum: &str,
+    cfg: &Config,
+) -> Result<bool> {
+    let err = |e| Error::Sql(format!("failed applying {}", r.path), e);
+    let t = begin(conn, &r.path, dir, cfg)?;
+    let current = format!(
+        "SELECT EXISTS (SELECT 1 FROM {} WHERE track = $1 AND filename = $2 AND checksum = $3)",
+        table
+    );
+    let params: &[&dyn ToSql] = &[&cfg.track, &r.path, &sum];
+    if done_elsewhere(within(conn, &t), cfg, &current, params)? {
+        info!("{} was applied by another migration", r.path);
+        events::emit(Event::Skipped {
+            version: None,
+            path: r.path.clone(),
+            reason: "applied by another migration".to_string(),
+        });
+        return Ok(false);
+    }
+    let start = Instant::now();
+    if let Err(e) = execute(
+        within(conn, &t),
+        &r.path,
+        d,
+        dir,
+        &format!("applying {}", r.path),
+        cfg,
+    ) {
+        if cfg.force && !cfg.past_deadline() {
+            warn!("continuing through error: {}", e);
+            events::emit(Event::Failed {
+                version: None,
+                path: r.path.clone(),
+                error: e.to_string(),
+            });
+            return Ok(false);
+        }
+        return Err(e);
+    }
+    let ms = start.elapsed().as_millis() as i64;
+    within(conn, &t)
+        .execute(
+            &SET_REPEATED.replace("{repeated}", table),
+            &[
+                &cfg.track,
+                &r.path,
+                &sum,
+                &ms,
+                &client_host(),
+                &cfg.revision,
+            ],
+        )
+        .map_err(|e| Error::Sql(format!("failed registering {}", r.path), e))?;
+    commit(t).map_err(err)?;
+    Ok(true)
+}
+
+pub(crate) fn revert(
+    conn: &dyn GenericConnection,
+    ver: i64,
+    path: &str,
+    d: &str,
+    dir: &Directives,
+    cfg: &Config,
+) -> Result<()> {
+    let unset_version = tracking::names(cfg, UNSET_VERSION);
+    let err = |e| Error::Sql(format!("failed reverting version {}", ver), e);
+    let t = begin(conn, path, dir, cfg)?;
+    let applied = tracking::names(cfg, &format!("SELECT NOT ({})", APPLIED));
+    if done_elsewhere(within(conn, &t), cfg, &applied, &[&cfg.track, &ver])? {
+        info!("version {} was reverted by another migration", ver);
+        return Ok(());
+    }
+    execute(
+        within(conn, &t),
+        path,
+        d,
+        dir,
+        &format!("reverting version {}", ver),
+        cfg,
+    )?;
+    within(conn, &t)
+        .execute(&unset_version, &[&ver, &cfg.track])
+        .map_err(|e| Error::Sql(format!("failed unregistering version {}", ver), e))?;
+    commit(t).map_err(err)
+}
+
+/// Change the recorded versions without running any schemata.
+pub(crate) fn fix(conn: &Connection, fixes: &[Fix], cfg: &Config) -> Result<()> {
+    let set_checksum = tracking::names(
+        cfg,
+        r#"
+        UPDATE {versions}
+        SET checksum = $2
+        WHERE version = $1
+        AND track = $3
+    "#,
+    );
+    let set_version = tracking::names(cfg, SET_VERSION);
+    let unset_version = tracking::names(cfg, UNSET_VERSION);
+    let err = |e| Error::Sql(format!("failed updating {}", cfg.versions_table()), e);
+    let t = conn.transaction().map_err(err)?;
+    for f in fixes {
+        match *f {
+            Fix::Checksum {
+                version,
+                ref checksum,
+                ..
+            } => t.execute(&set_checksum, &[&version, checksum, &cfg.track]),
+            Fix::Record {
+                version,
+                ref path,
+                ref description,
+                ref checksum,
+            } => t.execute(
+                &set_version,
+                &[
+                    &version,
+                    checksum,
+                    path,
+                    &None::<i64>,
+                    &client_host(),
+                    &true,
+                    &cfg.track,
+                    description,
+                    &cfg.revision,
+                    &labels(cfg),
+                ],
+            ),
+            Fix::Forget(version) => t.execute(&unset_version, &[&version, &cfg.track]),
+        }
+        .map_err(err)?;
+    }
+    t.commit().map_err(err)
+}
+
+/// Record versions applied by another tool, keeping when, how long, and by
+/// whom, and leaving any versions already recorded alone.
+pub(crate) fn import(conn: &Connection, records: &[VersionRecord], cfg: &Config) -> Result<()> {
+    let import_version = tracking::names(cfg, IMPORT_VERSION);
+    let err = |e| Error::Sql(format!("failed updating {}", cfg.versions_table()), e);
+    let t = conn.transaction().map_err(err)?;
+    for r in records {
+        t.execute(
+            &import_version,
+            &[
+                &r.version,
+                &r.applied,
+                &r.checksum,
+                &r.path,
+                &r.description,
+                &r.duration.map(|d| d.as_millis() as i64),
+                &r.applied_by,
+                &r.success,
+                &cfg.track,
+            ],
+        )
+        .map_err(err)?;
+    }
+    t.commit().map_err(err)
+}
+
+const IMPORT_VERSION: &str = r#"
+    INSERT INTO {versions}
+    (version, tstamp, checksum, filename, description, duration_ms, applied_by,
+     success, track, out_of_order)
+    VALUES
+    ($1, $2, $3, $4, $5, $6, COALESCE($7, current_user), $8, $9, false)
+    ON CONFLICT (track, version) DO NOTHING
+"#;
+
+/// Labels recorded as a JSON object, ignoring members that are not strings.
+fn parse_labels(text: &str) -> BTreeMap<String, String> {
+    match json::parse(text) {
+        Ok(json::Value::Object(members)) => members
+            .into_iter()
+            .filter_map(|(k, v)| v.as_str().map(|v| (k, v.to_string())))
+            .collect(),
+        _ => BTreeMap::new(),
+    }
+}
+
+/// The labels of the run as a JSON object, if it has any.
+fn labels(cfg: &Config) -> Option<String> {
+    if cfg.labels.is_empty() {
+        None
+    } else {
+        Some(json::object(&cfg.labels))
+    }
+}
+
+/// The name of the host schemato is running on.
+#[cfg(unix)]
+fn client_host() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn client_host() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_lock_keys_as_pg_locks_shows_them() {
+        assert_eq!(lock_ids(10297114116), (2, 1707179524));
+        assert_eq!(lock_ids(-1), (u32::MAX, u32::MAX));
+        assert_eq!(lock_ids(i64::MIN), (1 << 31, 0));
+        assert_eq!(lock_ids(-4294967296), (u32::MAX, 0));
+    }
+}
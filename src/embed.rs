@@ -0,0 +1,80 @@
+//! Embedding schemata in a binary at compile time.
+//!
+//! Call [`generate`] from the build script of the crate that will run the
+//! schemata, with schemato as a build dependency:
+//!
+//! ```no_run
+//! // In build.rs:
+//! schemato::embed::generate("schemata").unwrap();
+//! ```
+//!
+//! and then include the files with [`embed_migrations!`](crate::embed_migrations):
+//!
+//! ```ignore
+//! static SCHEMATA: &[schemato::EmbeddedFile] = schemato::embed_migrations!();
+//!
+//! schemato::Migrator::from_embedded(config, SCHEMATA)
+//!     .discover()?
+//!     .apply_all()?;
+//! ```
+
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::schemata::{include_of, list_sql_files};
+
+/// Name of the file written to `OUT_DIR` by [`generate`].
+pub const GENERATED: &str = "schemato_migrations.rs";
+
+/// Write a list of the SQL files in the directory, and of the files their
+/// include directives name, each included with `include_str!`, to `OUT_DIR`
+/// for [`embed_migrations!`](crate::embed_migrations).
+pub fn generate(dir: &str) -> Result<()> {
+    let out_dir = std::env::var("OUT_DIR").map_err(|_| {
+        Error::Config("OUT_DIR is not set; call generate from build.rs".to_string())
+    })?;
+    let abs = std::fs::canonicalize(dir)
+        .map_err(|e| Error::Io(format!("failed resolving {}", dir), e))?;
+
+    let mut names = list_sql_files(dir)?;
+    let mut i = 0;
+    while i < names.len() {
+        let path = abs.join(&names[i]);
+        let sql = std::fs::read_to_string(&path)
+            .map_err(|e| Error::Io(format!("failed reading {}", path.display()), e))?;
+        for include in sql.lines().filter_map(include_of) {
+            if !names.iter().any(|n| n == include) {
+                names.push(include.to_string());
+            }
+        }
+        i += 1;
+    }
+
+    let mut out = String::from("&[\n");
+    for name in names {
+        out.push_str(&format!(
+            "    ({:?}, include_str!({:?})),\n",
+            name,
+            abs.join(&name)
+        ));
+    }
+    out.push_str("]\n");
+
+    let path = Path::new(&out_dir).join(GENERATED);
+    std::fs::File::create(&path)
+        .and_then(|mut f| f.write_all(out.as_bytes()))
+        .map_err(|e| Error::Io(format!("failed writing {}", path.display()), e))?;
+
+    println!("cargo:rerun-if-changed={}", abs.display());
+    Ok(())
+}
+
+/// Include the schemata embedded by [`embed::generate`](crate::embed::generate)
+/// as a `&'static [EmbeddedFile]`.
+#[macro_export]
+macro_rules! embed_migrations {
+    () => {
+        include!(concat!(env!("OUT_DIR"), "/schemato_migrations.rs"))
+    };
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The configuration is unusable.
+    Config(String),
+    /// The server could not be reached.
+    Connect(String),
+    /// A schemata file could not be read or written.
+    Io(String, io::Error),
+    /// The advisory lock could not be obtained.
+    Locked(String),
+    /// A statement failed.
+    Sql(String, postgres::Error),
+    /// A statement of a schemata file failed. Holds where in the file, the
+    /// error, and notes such as the lines of the file around where it went
+    /// wrong.
+    Statement(String, postgres::Error, String),
+    /// Applied files no longer match the checksums recorded for them.
+    Modified(usize),
+    /// The schemata files and the installed versions cannot be reconciled.
+    Inconsistent(String),
+    /// A pre- or post-hook command failed.
+    Hook(String),
+    /// The run's deadline passed before it finished.
+    Deadline(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Config(ref msg)
+            | Error::Connect(ref msg)
+            | Error::Locked(ref msg)
+            | Error::Inconsistent(ref msg)
+            | Error::Hook(ref msg)
+            | Error::Deadline(ref msg) => {
+                write!(f, "{}", msg)
+            }
+            Error::Io(ref msg, ref e) => write!(f, "{}: {}", msg, e),
+            Error::Sql(ref msg, ref e) => write!(f, "{}: {}", msg, e),
+            Error::Statement(ref msg, ref e, ref excerpt) => {
+                write!(f, "{}: {}", msg, e)?;
+                if let Some(db) = e.as_db() {
+                    if let Some(ref detail) = db.detail {
+                        write!(f, "\nDETAIL: {}", detail)?;
+                    }
+                    if let Some(ref hint) = db.hint {
+                        write!(f, "\nHINT: {}", hint)?;
+                    }
+                    if let Some(ref context) = db.where_ {
+                        write!(f, "\nCONTEXT: {}", context)?;
+                    }
+                }
+                write!(f, "\n{}", excerpt)
+            }
+            Error::Modified(n) => write!(f, "{} applied versions have changed", n),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Io(_, ref e) => Some(e),
+            Error::Sql(_, ref e) | Error::Statement(_, ref e, _) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Machine-readable events marking the progress of a run, for orchestrators
+//! wrapping schemato to follow as it goes.
+//!
+//! Events go to every listener registered with [`listen`], from whichever
+//! thread the migrator runs on. [`ndjson`] registers one writing each event
+//! as a line of JSON.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::json;
+use crate::logging;
+
+/// Something that happened during a run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The schemata files were found.
+    Discovered { versions: usize, repeatables: usize },
+    /// The lock excluding other migrations was taken.
+    LockAcquired,
+    /// A run is about to take the number of steps given, each applying or
+    /// reverting a file.
+    Planned { steps: usize },
+    /// A file is about to be applied: a version, or a repeatable or seed file
+    /// without one.
+    Applying { version: Option<i64>, path: String },
+    /// A file was applied.
+    Applied {
+        version: Option<i64>,
+        path: String,
+        duration: Duration,
+    },
+    /// A statement of a file with more than one is about to run, counting
+    /// from 1.
+    Executing {
+        path: String,
+        statement: usize,
+        statements: usize,
+    },
+    /// A version is about to be reverted.
+    Reverting { version: i64, path: String },
+    /// A version was reverted.
+    Reverted {
+        version: i64,
+        path: String,
+        duration: Duration,
+    },
+    /// A file was left out of the run, for the reason given.
+    Skipped {
+        version: Option<i64>,
+        path: String,
+        reason: String,
+    },
+    /// Applying or reverting a file failed.
+    Failed {
+        version: Option<i64>,
+        path: String,
+        error: String,
+    },
+    /// The steps of a run were taken, leaving the version given as the
+    /// latest installed.
+    Installed { version: Option<i64> },
+    /// A command finished, having applied or reverted the number of files
+    /// given, or failed with the error given, with the run's labels.
+    Complete {
+        command: String,
+        outcome: std::result::Result<usize, String>,
+        duration: Duration,
+        labels: BTreeMap<String, String>,
+    },
+}
+
+impl Event {
+    /// The name of the event, as written in its `event` field.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::Discovered { .. } => "discovered",
+            Event::LockAcquired => "lock_acquired",
+            Event::Planned { .. } => "planned",
+            Event::Applying { .. } => "applying",
+            Event::Applied { .. } => "applied",
+            Event::Executing { .. } => "executing",
+            Event::Reverting { .. } => "reverting",
+            Event::Reverted { .. } => "reverted",
+            Event::Skipped { .. } => "skipped",
+            Event::Failed { .. } => "failed",
+            Event::Installed { .. } => "installed",
+            Event::Complete { .. } => "complete",
+        }
+    }
+
+    /// The event as a line of JSON, without the line break, with when it
+    /// happened and the database it happened to.
+    pub fn to_json(&self) -> String {
+        let mut members = vec![
+            ("event", json::string(self.name())),
+            (
+                "timestamp",
+                json::string(&Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+            ),
+        ];
+        if let Some(database) = logging::current().database {
+            members.push(("database", json::string(&database)));
+        }
+        let version = |v: Option<i64>| v.map_or("null".to_string(), |v| v.to_string());
+        let ms = |d: &Duration| d.as_millis().to_string();
+        match *self {
+            Event::Discovered {
+                versions,
+                repeatables,
+            } => {
+                members.push(("versions", versions.to_string()));
+                members.push(("repeatables", repeatables.to_string()));
+            }
+            Event::LockAcquired => {}
+            Event::Planned { steps } => members.push(("steps", steps.to_string())),
+            Event::Executing {
+                ref path,
+                statement,
+                statements,
+            } => {
+                members.push(("path", json::string(path)));
+                members.push(("statement", statement.to_string()));
+                members.push(("statements", statements.to_string()));
+            }
+            Event::Applying {
+                version: v,
+                ref path,
+            } => {
+                members.push(("version", version(v)));
+                members.push(("path", json::string(path)));
+            }
+            Event::Applied {
+                version: v,
+                ref path,
+                ref duration,
+            } => {
+                members.push(("version", version(v)));
+                members.push(("path", json::string(path)));
+                members.push(("duration_ms", ms(duration)));
+            }
+            Event::Reverting {
+                version: v,
+                ref path,
+            } => {
+                members.push(("version", v.to_string()));
+                members.push(("path", json::string(path)));
+            }
+            Event::Reverted {
+                version: v,
+                ref path,
+                ref duration,
+            } => {
+                members.push(("version", v.to_string()));
+                members.push(("path", json::string(path)));
+                members.push(("duration_ms", ms(duration)));
+            }
+            Event::Skipped {
+                version: v,
+                ref path,
+                ref reason,
+            } => {
+                members.push(("version", version(v)));
+                members.push(("path", json::string(path)));
+                members.push(("reason", json::string(reason)));
+            }
+            Event::Failed {
+                version: v,
+                ref path,
+                ref error,
+            } => {
+                members.push(("version", version(v)));
+                members.push(("path", json::string(path)));
+                members.push(("error", json::string(error)));
+            }
+            Event::Installed { version: v } => members.push(("version", version(v))),
+            Event::Complete {
+                ref command,
+                ref outcome,
+                ref duration,
+                ref labels,
+            } => {
+                members.push(("command", json::string(command)));
+                match *outcome {
+                    Ok(changed) => {
+                        members.push(("outcome", json::string("success")));
+                        members.push(("changed", changed.to_string()));
+                    }
+                    Err(ref error) => {
+                        members.push(("outcome", json::string("failure")));
+                        members.push(("error", json::string(error)));
+                    }
+                }
+                members.push(("duration_ms", ms(duration)));
+                if !labels.is_empty() {
+                    members.push(("labels", json::object(labels)));
+                }
+            }
+        }
+        let members: Vec<String> = members
+            .iter()
+            .map(|(k, v)| format!("{}: {}", json::string(k), v))
+            .collect();
+        format!("{{{}}}", members.join(", "))
+    }
+}
+
+type Listener = Box<dyn Fn(&Event) + Send>;
+
+static LISTENERS: Mutex<Vec<Listener>> = Mutex::new(Vec::new());
+
+/// Call `f` with every event from now on.
+pub fn listen<F: Fn(&Event) + Send + 'static>(f: F) {
+    LISTENERS.lock().unwrap().push(Box::new(f));
+}
+
+/// Write every event from now on to `out` as newline-delimited JSON,
+/// flushing after each. Failures to write are logged once.
+pub fn ndjson<W: Write + Send + 'static>(out: W) {
+    let out = Mutex::new((out, false));
+    listen(move |event| {
+        let mut out = out.lock().unwrap();
+        let written = writeln!(out.0, "{}", event.to_json()).and_then(|_| out.0.flush());
+        if let Err(e) = written {
+            if !out.1 {
+                warn!("failed writing events: {}", e);
+                out.1 = true;
+            }
+        }
+    });
+}
+
+thread_local! {
+    /// The events of the current thread gathered by [`collect`], if it is
+    /// running.
+    static COLLECTED: RefCell<Option<Vec<Event>>> = const { RefCell::new(None) };
+    /// Whether the current thread's events are being dropped by [`mute`].
+    static MUTED: RefCell<bool> = const { RefCell::new(false) };
+}
+
+/// Run `f`, returning what it returns along with the events it emitted on
+/// the current thread.
+pub(crate) fn collect<T, F: FnOnce() -> T>(f: F) -> (T, Vec<Event>) {
+    let outer = COLLECTED.with(|c| c.replace(Some(Vec::new())));
+    let result = f();
+    let events = COLLECTED.with(|c| c.replace(outer)).unwrap_or_default();
+    COLLECTED.with(|c| {
+        if let Some(ref mut outer) = *c.borrow_mut() {
+            outer.extend(events.iter().cloned());
+        }
+    });
+    (result, events)
+}
+
+/// Run `f` without emitting its events, as for building a scratch database
+/// that events are not about.
+pub(crate) fn mute<T, F: FnOnce() -> T>(f: F) -> T {
+    let outer = MUTED.with(|m| m.replace(true));
+    let result = f();
+    MUTED.with(|m| *m.borrow_mut() = outer);
+    result
+}
+
+/// Whether any listener has been registered, for skipping work done only to
+/// report an event.
+pub(crate) fn listening() -> bool {
+    !LISTENERS.lock().unwrap().is_empty()
+}
+
+/// Send the event to the listeners.
+pub(crate) fn emit(event: Event) {
+    if MUTED.with(|m| *m.borrow()) {
+        return;
+    }
+    COLLECTED.with(|c| {
+        if let Some(ref mut collected) = *c.borrow_mut() {
+            collected.push(event.clone());
+        }
+    });
+    for listener in LISTENERS.lock().unwrap().iter() {
+        listener(&event);
+    }
+}
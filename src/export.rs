@@ -0,0 +1,107 @@
+//! The installed versions written out for archives and dashboards.
+
+use crate::config::Config;
+use crate::db::VersionRecord;
+use crate::json;
+
+/// The fields of each version, in the order written.
+const FIELDS: &[&str] = &[
+    "database",
+    "track",
+    "version",
+    "applied",
+    "checksum",
+    "filename",
+    "description",
+    "duration_ms",
+    "applied_by",
+    "client_host",
+    "revision",
+    "success",
+    "out_of_order",
+    "labels",
+];
+
+/// The fields of a version, unquoted, with `None` for those not recorded.
+fn fields(cfg: &Config, r: &VersionRecord) -> Vec<Option<String>> {
+    vec![
+        Some(cfg.db_name.clone()),
+        Some(cfg.track.clone()),
+        Some(r.version.to_string()),
+        Some(r.applied.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+        r.checksum.clone(),
+        r.path.clone(),
+        r.description.clone(),
+        r.duration.map(|d| d.as_millis().to_string()),
+        r.applied_by.clone(),
+        r.client_host.clone(),
+        r.revision.clone(),
+        Some(r.success.to_string()),
+        Some(r.out_of_order.to_string()),
+        if r.labels.is_empty() {
+            None
+        } else {
+            Some(json::object(&r.labels))
+        },
+    ]
+}
+
+/// The versions as a JSON array, a version to a line.
+pub(crate) fn to_json(cfg: &Config, records: &[VersionRecord]) -> String {
+    // Numbers, booleans, and the labels' object are written bare, the rest
+    // as strings.
+    let bare = [
+        "version",
+        "duration_ms",
+        "success",
+        "out_of_order",
+        "labels",
+    ];
+    let lines: Vec<String> = records
+        .iter()
+        .map(|r| {
+            let members: Vec<String> = FIELDS
+                .iter()
+                .zip(fields(cfg, r))
+                .map(|(name, value)| {
+                    let value = match value {
+                        None => "null".to_string(),
+                        Some(v) if bare.contains(name) => v,
+                        Some(v) => json::string(&v),
+                    };
+                    format!("{}: {}", json::string(name), value)
+                })
+                .collect();
+            format!("  {{{}}}", members.join(", "))
+        })
+        .collect();
+    if lines.is_empty() {
+        return "[]\n".to_string();
+    }
+    format!("[\n{}\n]\n", lines.join(",\n"))
+}
+
+/// The versions as CSV with a header row, leaving fields not recorded
+/// empty.
+pub(crate) fn to_csv(cfg: &Config, records: &[VersionRecord]) -> String {
+    let mut csv = FIELDS.join(",");
+    csv.push_str("\r\n");
+    for r in records {
+        let row: Vec<String> = fields(cfg, r)
+            .into_iter()
+            .map(|value| csv_field(&value.unwrap_or_default()))
+            .collect();
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// A CSV field, quoted if it holds a comma, quote, or line break.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
@@ -0,0 +1,114 @@
+//! Shell commands run before and after the runs that change the database.
+//!
+//! Each hook receives the details of the run in environment variables:
+//! `SCHEMATO_RUN_COMMAND`, `SCHEMATO_RUN_DATABASE`, `SCHEMATO_RUN_HOST`,
+//! `SCHEMATO_RUN_PORT`, and `SCHEMATO_RUN_TRACK`, and after the run
+//! `SCHEMATO_RUN_OUTCOME` (`success` or `failure`) with either
+//! `SCHEMATO_RUN_CHANGED`, the number of files applied or reverted, or
+//! `SCHEMATO_RUN_ERROR`.
+
+use std::process::Command;
+use std::time::Instant;
+
+use chrono::Utc;
+
+use crate::config::Config;
+use crate::db;
+use crate::error::{Error, Result};
+use crate::events::{self, Event};
+
+/// Run `f` for `command` between the configured pre- and post-hooks. A
+/// failing pre-hook prevents the run; a failing post-hook fails a run that
+/// otherwise succeeded. The run is recorded in the runs table whatever its
+/// outcome.
+pub(crate) fn around<F>(cfg: &Config, command: &str, f: F) -> Result<usize>
+where
+    F: FnOnce() -> Result<usize>,
+{
+    let mut env = vec![
+        ("SCHEMATO_RUN_COMMAND", command.to_string()),
+        ("SCHEMATO_RUN_DATABASE", cfg.db_name.clone()),
+        ("SCHEMATO_RUN_HOST", cfg.db_host.clone()),
+        ("SCHEMATO_RUN_PORT", cfg.db_port.to_string()),
+        ("SCHEMATO_RUN_TRACK", cfg.track.clone()),
+    ];
+    if let Some(ref hook) = cfg.pre_hook {
+        run("pre-hook", hook, &env)?;
+    }
+
+    let (started, start) = (Utc::now(), Instant::now());
+    let (result, emitted) = events::collect(f);
+    let duration = start.elapsed();
+    record(cfg, command, started, &result, &emitted);
+    events::emit(Event::Complete {
+        command: command.to_string(),
+        outcome: result.as_ref().map(|&n| n).map_err(|e| e.to_string()),
+        duration,
+        labels: cfg.labels.clone(),
+    });
+
+    if let Some(ref hook) = cfg.post_hook {
+        match result {
+            Ok(changed) => {
+                env.push(("SCHEMATO_RUN_OUTCOME", "success".to_string()));
+                env.push(("SCHEMATO_RUN_CHANGED", changed.to_string()));
+            }
+            Err(ref e) => {
+                env.push(("SCHEMATO_RUN_OUTCOME", "failure".to_string()));
+                env.push(("SCHEMATO_RUN_ERROR", e.to_string()));
+            }
+        }
+        match run("post-hook", hook, &env) {
+            Ok(()) => {}
+            Err(e) if result.is_ok() => return Err(e),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    result
+}
+
+/// Record the run, warning rather than failing if it cannot be, as the run
+/// itself is done by now.
+fn record(
+    cfg: &Config,
+    command: &str,
+    started: chrono::DateTime<Utc>,
+    result: &Result<usize>,
+    emitted: &[Event],
+) {
+    // There is nowhere to record a run that could not connect, and one
+    // attempt will do for the rest, as the run has reported any trouble.
+    if let Err(Error::Connect(_)) = *result {
+        return;
+    }
+    let mut cfg = cfg.clone();
+    cfg.attempts = 1;
+    match db::record_run(&cfg, command, started, result, emitted) {
+        Ok(true) => debug!("recorded the run in {}", cfg.runs_table()),
+        Ok(false) => debug!("{} does not exist; not recording the run", cfg.runs_table()),
+        Err(e) => warn!("failed recording the run: {}", e),
+    }
+}
+
+fn run(name: &str, hook: &str, env: &[(&str, String)]) -> Result<()> {
+    info!("running {}", name);
+    debug!("{}: {}", name, hook);
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    let status = cmd
+        .arg(hook)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .status()
+        .map_err(|e| Error::Hook(format!("failed running {}: {}", name, e)))?;
+    if !status.success() {
+        return Err(Error::Hook(format!("{} failed with {}", name, status)));
+    }
+    Ok(())
+}
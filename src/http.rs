@@ -0,0 +1,189 @@
+//! Just enough of an HTTP client to report runs to other services and fetch
+//! credentials from them.
+//!
+//! Plain `http://` URLs are requested directly. `https://` URLs are handed
+//! to `curl`, which must be installed, rather than bringing in a TLS stack.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// How long to wait for a service before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send `body` to `url` with `method`, failing unless the response has a
+/// 2xx status.
+pub(crate) fn send(method: &str, url: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    request(method, url, &[], content_type, body).map(|_| ())
+}
+
+/// Send `body` to `url` with `method` and the extra `headers`, returning the
+/// body of the response, failing unless it has a 2xx status.
+pub(crate) fn request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<String> {
+    if url.starts_with("https://") {
+        return curl(method, url, headers, content_type, body);
+    }
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not an http:// or https:// URL", url),
+        )
+    })?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve", authority),
+        )
+    })?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let extra: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: schemato/{}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        authority,
+        env!("CARGO_PKG_VERSION"),
+        extra,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    let status = status.trim_end().to_string();
+    let mut chunked = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+        let header = header.to_ascii_lowercase();
+        chunked |= header.starts_with("transfer-encoding:") && header.contains("chunked");
+    }
+    let mut response = String::new();
+    if chunked {
+        response = unchunk(&mut reader)?;
+    } else {
+        reader.take(1 << 20).read_to_string(&mut response)?;
+    }
+    let code = status.split_whitespace().nth(1).unwrap_or("");
+    if code.starts_with('2') {
+        return Ok(response);
+    }
+    let detail: String = response.trim().chars().take(4096).collect();
+    Err(io::Error::other(format!(
+        "{} responded {}{}{}",
+        url,
+        status,
+        if detail.is_empty() { "" } else { ": " },
+        detail
+    )))
+}
+
+/// Percent-encode all but the unreserved characters of a URL.
+pub(crate) fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Read a body sent with chunked transfer encoding.
+fn unchunk<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut body = Vec::new();
+    loop {
+        let mut size = String::new();
+        reader.read_line(&mut size)?;
+        let size = size.trim_end().split(';').next().unwrap_or("");
+        let size = usize::from_str_radix(size.trim(), 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        let mut end = String::new();
+        reader.read_line(&mut end)?;
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+fn curl(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<String> {
+    let mut cmd = Command::new("curl");
+    cmd.args(["--silent", "--show-error", "--fail", "--max-time"])
+        .arg(TIMEOUT.as_secs().to_string())
+        .args(["--request", method, "--header"])
+        .arg(format!("Content-Type: {}", content_type));
+    // Headers may hold secrets, so they are read from stdin rather than
+    // shown in the process list, and the body is given in their place.
+    let stdin = if headers.is_empty() {
+        cmd.args(["--data-binary", "@-"]);
+        body.to_vec()
+    } else {
+        cmd.args(["--header", "@-"]);
+        if !body.is_empty() {
+            cmd.arg("--data-binary")
+                .arg(String::from_utf8_lossy(body).as_ref());
+        }
+        headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\n", name, value))
+            .collect::<String>()
+            .into_bytes()
+    };
+    let mut child = cmd
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed running curl: {}", e)))?;
+    child.stdin.take().unwrap().write_all(&stdin)?;
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(io::Error::other(format!(
+            "curl failed sending to {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
@@ -0,0 +1,162 @@
+//! Reading the history kept by other migration tools, so that schemato can
+//! take over their databases without baselining each one by hand.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use postgres::GenericConnection;
+
+use crate::config::{Config, ImportSource};
+use crate::db;
+use crate::error::{Error, Result};
+
+/// A version the other tool applied, or tried to.
+#[derive(Clone, Debug)]
+pub(crate) struct Applied {
+    /// The version as the other tool names it.
+    pub version: String,
+    pub description: Option<String>,
+    /// The file it was applied from.
+    pub script: Option<String>,
+    pub applied: Option<DateTime<Utc>>,
+    pub applied_by: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub success: bool,
+    /// Whether it stands for every version up to it, as a Flyway baseline
+    /// does.
+    pub baseline: bool,
+    /// Whether it records the version being reverted or deleted rather than
+    /// applied.
+    pub undo: bool,
+}
+
+/// The table the tool keeps its history in unless configured otherwise.
+pub(crate) fn default_table(source: ImportSource) -> &'static str {
+    match source {
+        ImportSource::Flyway => "flyway_schema_history",
+        ImportSource::GolangMigrate => "schema_migrations",
+        ImportSource::Diesel => "__diesel_schema_migrations",
+        ImportSource::Sqlx => "_sqlx_migrations",
+        ImportSource::Refinery => "refinery_schema_history",
+    }
+}
+
+/// Read a file mapping the other tool's versions to schemato's, for when
+/// they are numbered differently. Each line maps one version, as
+/// `1.1 = 2`; blank lines and those starting with `#` are ignored.
+pub(crate) fn read_mapping(path: &str) -> Result<BTreeMap<String, i64>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::Io(format!("failed reading {}", path), e))?;
+    let mut mapping = BTreeMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad = || Error::Config(format!("bad mapping on line {} of {}", n + 1, path));
+        let (from, to) = line.split_once('=').ok_or_else(bad)?;
+        let to: i64 = to.trim().parse().map_err(|_| bad())?;
+        mapping.insert(from.trim().to_string(), to);
+    }
+    Ok(mapping)
+}
+
+/// Read the versions recorded in the tool's table, in the order they were
+/// applied. The table may be schema-qualified.
+pub(crate) fn read(
+    conn: &dyn GenericConnection,
+    cfg: &Config,
+    source: ImportSource,
+    table: &str,
+) -> Result<Vec<Applied>> {
+    let name = table
+        .split('.')
+        .map(db::ident)
+        .collect::<Result<Vec<_>>>()?
+        .join(".");
+    let query = match source {
+        ImportSource::Flyway => FLYWAY,
+        ImportSource::GolangMigrate => GOLANG_MIGRATE,
+        ImportSource::Diesel => DIESEL,
+        ImportSource::Sqlx => SQLX,
+        ImportSource::Refinery => REFINERY,
+    }
+    .replace("{table}", &name);
+    let rows = conn
+        .query(&query, &[])
+        .map_err(|e| Error::Sql(format!("failed reading {} in {}", table, cfg.db_name), e))?;
+
+    let mut applied = Vec::new();
+    for row in &rows {
+        let version: Option<String> = row.get(0);
+        let script: Option<String> = row.get(2);
+        let version = match version {
+            Some(version) => version,
+            None => {
+                warn!(
+                    "not importing repeatable {}, which schemato will apply again",
+                    script.as_deref().unwrap_or("-")
+                );
+                continue;
+            }
+        };
+        let kind: String = row.get(7);
+        if kind == "SCHEMA" {
+            continue;
+        }
+        applied.push(Applied {
+            version,
+            description: row.get(1),
+            script,
+            applied: row.get(3),
+            applied_by: row.get(4),
+            duration_ms: row.get(5),
+            success: row.get(6),
+            baseline: kind.ends_with("BASELINE"),
+            undo: kind.starts_with("UNDO") || kind == "DELETE",
+        });
+    }
+    Ok(applied)
+}
+
+/// Flyway's history, with repeatable migrations having no version. The type
+/// tells baselines, undos, and the creation of schemas from migrations.
+const FLYWAY: &str = r#"
+    SELECT version, description, script, installed_on::timestamptz, installed_by,
+        execution_time::bigint, success, type
+    FROM {table}
+    ORDER BY installed_rank
+"#;
+
+// The other tools' histories, in the form of Flyway's.
+
+/// golang-migrate's only row, the latest version, which stands for every
+/// version up to it and is dirty if applying it failed.
+const GOLANG_MIGRATE: &str = r#"
+    SELECT version::text, NULL::text, NULL::text, NULL::timestamptz, NULL::text,
+        NULL::bigint, NOT dirty, 'BASELINE'::text
+    FROM {table}
+"#;
+
+const DIESEL: &str = r#"
+    SELECT version::text, NULL::text, NULL::text, run_on::timestamptz, NULL::text,
+        NULL::bigint, true, 'SQL'::text
+    FROM {table}
+    ORDER BY run_on, version
+"#;
+
+/// SQLx's history, which times each version in nanoseconds.
+const SQLX: &str = r#"
+    SELECT version::text, description, NULL::text, installed_on, NULL::text,
+        (execution_time / 1000000)::bigint, success, 'SQL'::text
+    FROM {table}
+    ORDER BY installed_on, version
+"#;
+
+/// Refinery's history, which keeps when each version was applied as text.
+const REFINERY: &str = r#"
+    SELECT version::text, name::text, NULL::text, applied_on::timestamptz, NULL::text,
+        NULL::bigint, true, 'SQL'::text
+    FROM {table}
+    ORDER BY version
+"#;
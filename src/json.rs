@@ -0,0 +1,292 @@
+//! Just enough JSON for schemato's own files and output.
+
+use std::collections::BTreeMap;
+
+/// The string as a JSON string literal.
+pub(crate) fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The pairs as a JSON object of strings.
+pub(crate) fn object(pairs: &BTreeMap<String, String>) -> String {
+    let members: Vec<String> = pairs
+        .iter()
+        .map(|(k, v)| format!("{}: {}", string(k), string(v)))
+        .collect();
+    format!("{{{}}}", members.join(", "))
+}
+
+/// A parsed JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The member of an object with the given key.
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+            _ => None,
+        }
+    }
+
+    /// The string, if this is one.
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The number, if this is one.
+    pub(crate) fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a JSON document, saying where it went wrong if it is not one.
+pub(crate) fn parse(text: &str) -> std::result::Result<Value, String> {
+    let mut p = Parser {
+        chars: text.char_indices().peekable(),
+        text,
+    };
+    let value = p.value()?;
+    p.space();
+    match p.chars.next() {
+        None => Ok(value),
+        Some((i, _)) => Err(p.at(i, "trailing characters")),
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    text: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    /// An error at a byte offset, given as a line number.
+    fn at(&self, i: usize, what: &str) -> String {
+        format!(
+            "{} on line {}",
+            what,
+            self.text[..i].matches('\n').count() + 1
+        )
+    }
+
+    fn end(&self) -> String {
+        self.at(self.text.len(), "unexpected end")
+    }
+
+    fn space(&mut self) {
+        while let Some(&(_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, want: char) -> std::result::Result<(), String> {
+        self.space();
+        match self.chars.next() {
+            Some((_, c)) if c == want => Ok(()),
+            Some((i, _)) => Err(self.at(i, &format!("expected {}", want))),
+            None => Err(self.end()),
+        }
+    }
+
+    fn value(&mut self) -> std::result::Result<Value, String> {
+        self.space();
+        let (i, c) = match self.chars.peek() {
+            Some(&next) => next,
+            None => return Err(self.end()),
+        };
+        match c {
+            '{' => {
+                self.chars.next();
+                let mut members = Vec::new();
+                self.space();
+                if let Some(&(_, '}')) = self.chars.peek() {
+                    self.chars.next();
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.expect('"')?;
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    members.push((key, self.value()?));
+                    self.space();
+                    match self.chars.next() {
+                        Some((_, ',')) => continue,
+                        Some((_, '}')) => return Ok(Value::Object(members)),
+                        Some((i, _)) => return Err(self.at(i, "expected , or }")),
+                        None => return Err(self.end()),
+                    }
+                }
+            }
+            '[' => {
+                self.chars.next();
+                let mut items = Vec::new();
+                self.space();
+                if let Some(&(_, ']')) = self.chars.peek() {
+                    self.chars.next();
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.space();
+                    match self.chars.next() {
+                        Some((_, ',')) => continue,
+                        Some((_, ']')) => return Ok(Value::Array(items)),
+                        Some((i, _)) => return Err(self.at(i, "expected , or ]")),
+                        None => return Err(self.end()),
+                    }
+                }
+            }
+            '"' => {
+                self.chars.next();
+                Ok(Value::String(self.string()?))
+            }
+            _ => {
+                let rest = &self.text[i..];
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c)))
+                    .unwrap_or(rest.len());
+                let word = &rest[..len];
+                let value = match word {
+                    "null" => Value::Null,
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => Value::Number(word.parse().map_err(|_| self.at(i, "unexpected value"))?),
+                };
+                for _ in word.chars() {
+                    self.chars.next();
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    /// The rest of a string whose opening quote has been read.
+    fn string(&mut self) -> std::result::Result<String, String> {
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(s),
+                Some((i, '\\')) => match self.chars.next().map(|(_, c)| c) {
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| self.chars.next())
+                            .map(|c| c.1)
+                            .collect();
+                        let code =
+                            u32::from_str_radix(&hex, 16).map_err(|_| self.at(i, "bad escape"))?;
+                        s.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    Some(c) => s.push(c),
+                    None => return Err(self.end()),
+                },
+                Some((_, c)) => s.push(c),
+                None => return Err(self.end()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(string("plain"), "\"plain\"");
+        assert_eq!(
+            string("a\"b\\c\nd\te\u{1}"),
+            "\"a\\\"b\\\\c\\nd\\te\\u0001\""
+        );
+    }
+
+    #[test]
+    fn writes_objects_of_strings() {
+        let mut pairs = BTreeMap::new();
+        assert_eq!(object(&pairs), "{}");
+        pairs.insert("ticket".to_string(), "OPS-12".to_string());
+        pairs.insert("note".to_string(), "say \"hi\"".to_string());
+        assert_eq!(
+            object(&pairs),
+            "{\"note\": \"say \\\"hi\\\"\", \"ticket\": \"OPS-12\"}"
+        );
+    }
+
+    #[test]
+    fn parses_documents() {
+        let value = parse(
+            r#" {"data": {"username": "v-app", "n": -1.5e2, "ok": true, "none": null},
+                 "list": [1, "two", []], "esc": "a\"é\n"} "#,
+        )
+        .unwrap();
+        let data = value.get("data").unwrap();
+        assert_eq!(data.get("username").and_then(Value::as_str), Some("v-app"));
+        assert_eq!(data.get("n").and_then(Value::as_f64), Some(-150.0));
+        assert_eq!(data.get("ok"), Some(&Value::Bool(true)));
+        assert_eq!(data.get("none"), Some(&Value::Null));
+        assert_eq!(data.get("missing"), None);
+        assert_eq!(
+            value.get("list"),
+            Some(&Value::Array(vec![
+                Value::Number(1.0),
+                Value::String("two".to_string()),
+                Value::Array(Vec::new()),
+            ]))
+        );
+        assert_eq!(value.get("esc").and_then(Value::as_str), Some("a\"é\n"));
+    }
+
+    #[test]
+    fn round_trips_what_it_writes() {
+        let text = "tab\tquote\" back\\ bell\u{7}";
+        assert_eq!(parse(&string(text)), Ok(Value::String(text.to_string())));
+    }
+
+    #[test]
+    fn says_where_documents_are_bad() {
+        assert_eq!(
+            parse("{\"a\": 1,\n \"b\" 2}"),
+            Err("expected : on line 2".to_string())
+        );
+        assert_eq!(parse("[1, 2"), Err("unexpected end on line 1".to_string()));
+        assert_eq!(
+            parse("{} x"),
+            Err("trailing characters on line 1".to_string())
+        );
+        assert_eq!(parse("nope"), Err("unexpected value on line 1".to_string()));
+    }
+}
@@ -0,0 +1,63 @@
+//! Database migration management for postgres-backed applications.
+//!
+//! Schemata are SQL files named for their version, such as `0001.sql`, kept
+//! together in a directory. A [`Migrator`] discovers them and applies any
+//! that are not yet recorded in the `schemato.versions` table of the target
+//! database, creating the database and table first if necessary.
+//!
+//! ```no_run
+//! use schemato::{Config, Migrator};
+//!
+//! let mut config = Config::new("app");
+//! config.prefix = "schemata".to_string();
+//!
+//! Migrator::new(config).discover()?.apply_all()?;
+//! # Ok::<(), schemato::Error>(())
+//! ```
+
+#[macro_use]
+extern crate log;
+
+#[cfg(feature = "blake3")]
+mod blake3;
+mod catalog;
+mod config;
+mod db;
+pub mod embed;
+mod error;
+pub mod events;
+mod export;
+mod hooks;
+mod http;
+mod import;
+mod json;
+pub mod logging;
+pub mod metrics;
+mod migrator;
+pub mod notify;
+mod pgpass;
+pub mod profile;
+pub mod prompt;
+mod schemata;
+#[cfg(feature = "sentry")]
+pub mod sentry;
+pub mod service;
+mod signatures;
+mod statements;
+pub mod summary;
+mod tracking;
+mod tunnel;
+pub mod vault;
+
+pub use crate::config::{
+    parse_duration, Access, ChecksumAlgorithm, Config, ExportFormat, ImportSource, LockStrategy,
+    OutOfOrder, Role, SnapshotFormat, TargetSessionAttrs, APPLICATION_NAME,
+};
+pub use crate::db::VersionRecord;
+pub use crate::error::{Error, Result};
+pub use crate::migrator::{
+    Difference, Divergence, Drift, Fix, Migrator, Repairs, State, Step, VersionStatus,
+};
+pub use crate::schemata::{
+    checksum, checksum_with, create_next, EmbeddedFile, Migration, Repeatable,
+};
@@ -0,0 +1,444 @@
+//! Structured log lines, for log aggregators that would otherwise parse
+//! schemato's text with regular expressions.
+//!
+//! schemato logs through the `log` crate. Alongside each line, it keeps the
+//! database it is working on and, while applying or reverting a version,
+//! that version and, once it is done, how long it took. [`json`] writes a
+//! record as a single JSON object with those fields, for a logger to use as
+//! its format. [`LogFile`] keeps the lines in a file that is rotated by
+//! size, and on Unix, [`Syslog`] and [`Journald`] send them to the system
+//! log. Whichever way a line is written, [`redact`] first hides the
+//! credentials in it.
+
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::json;
+use crate::statements;
+
+/// The keys, or the ends of keys such as `client_secret` or
+/// `X-Vault-Token`, whose values [`redact`] hides.
+const CREDENTIAL_KEYS: &[&str] = &["password", "secret", "token"];
+
+/// The message with the credentials in it replaced with `********`: the
+/// passwords of any URIs, as in `postgres://app:secret@db`, passwords given
+/// in SQL, as in `PASSWORD 'secret'`, and the values of password, secret,
+/// and token fields, as in `password=secret` or `"access_token": "secret"`.
+/// The rest of the message is left alone.
+pub fn redact(message: &str) -> String {
+    let message = redact_fields(&statements::redact(message));
+    let mut out = String::with_capacity(message.len());
+    let mut rest = message.as_str();
+    while let Some(i) = rest.find("://") {
+        let (before, after) = rest.split_at(i + 3);
+        out.push_str(before);
+        let end = after
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .unwrap_or(after.len());
+        match after[..end].rfind('@') {
+            Some(at) => {
+                match after[..at].split_once(':') {
+                    Some((user, _)) => {
+                        out.push_str(user);
+                        out.push_str(":********");
+                    }
+                    None => out.push_str(&after[..at]),
+                }
+                rest = &after[at..];
+            }
+            None => rest = after,
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The message with the values of its credential fields, in `key=value`,
+/// `key: value`, or JSON form, replaced with `********`.
+fn redact_fields(message: &str) -> String {
+    let bytes = message.as_bytes();
+    let is_key_char = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'-';
+    let mut out = String::with_capacity(message.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let key = CREDENTIAL_KEYS.iter().find(|key| {
+            bytes[i..]
+                .get(..key.len())
+                .is_some_and(|b| b.eq_ignore_ascii_case(key.as_bytes()))
+        });
+        let end = match key {
+            Some(key) => i + key.len(),
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        if bytes.get(end).is_some_and(|&b| is_key_char(b)) {
+            i = end;
+            continue;
+        }
+        // The key may be quoted, as in JSON, and is followed by `=` or `:`.
+        let mut j = end;
+        if bytes.get(j) == Some(&b'"') {
+            j += 1;
+        }
+        while bytes.get(j) == Some(&b' ') {
+            j += 1;
+        }
+        if !matches!(bytes.get(j), Some(b'=') | Some(b':')) {
+            i = end;
+            continue;
+        }
+        j += 1;
+        while bytes.get(j) == Some(&b' ') {
+            j += 1;
+        }
+        let (start, stop) = if bytes.get(j) == Some(&b'"') {
+            let mut k = j + 1;
+            while k < bytes.len() && bytes[k] != b'"' {
+                k += if bytes[k] == b'\\' { 2 } else { 1 };
+            }
+            (j + 1, k.min(bytes.len()))
+        } else {
+            let k = bytes[j..]
+                .iter()
+                .position(|&b| b.is_ascii_whitespace() || b"&,;}\"'".contains(&b))
+                .map_or(bytes.len(), |p| j + p);
+            (j, k)
+        };
+        if stop > start {
+            out.push_str(&message[copied..start]);
+            out.push_str("********");
+            copied = stop;
+        }
+        i = stop.max(end);
+    }
+    out.push_str(&message[copied..]);
+    out
+}
+
+/// What the lines logged by the current thread are about.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fields {
+    pub database: Option<String>,
+    pub version: Option<i64>,
+    pub duration: Option<Duration>,
+}
+
+thread_local! {
+    static FIELDS: RefCell<Fields> = RefCell::new(Fields::default());
+}
+
+/// The fields of the current thread's log lines.
+pub fn current() -> Fields {
+    FIELDS.with(|fields| fields.borrow().clone())
+}
+
+/// Set the database the current thread's log lines are about.
+pub(crate) fn set_database(name: &str) {
+    FIELDS.with(|fields| fields.borrow_mut().database = Some(name.to_string()));
+}
+
+/// Change the fields with `set` until the returned guard is dropped.
+pub(crate) fn scope<S: FnOnce(&mut Fields)>(set: S) -> Restore {
+    let saved = current();
+    FIELDS.with(|fields| set(&mut fields.borrow_mut()));
+    Restore(saved)
+}
+
+/// Restores the fields as they were before [`scope`] when dropped.
+pub(crate) struct Restore(Fields);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let saved = std::mem::take(&mut self.0);
+        FIELDS.with(|fields| *fields.borrow_mut() = saved);
+    }
+}
+
+/// The record as a line of JSON with its time, level, and message as the
+/// `event`, and the current thread's [`Fields`] where they are known.
+pub fn json(record: &log::Record) -> String {
+    let fields = current();
+    let mut members = vec![
+        format!(
+            "\"timestamp\": {}",
+            json::string(&Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())
+        ),
+        format!("\"level\": {}", json::string(&record.level().to_string())),
+        format!(
+            "\"event\": {}",
+            json::string(&redact(&record.args().to_string()))
+        ),
+    ];
+    if let Some(ref database) = fields.database {
+        members.push(format!("\"database\": {}", json::string(database)));
+    }
+    if let Some(version) = fields.version {
+        members.push(format!("\"version\": {}", version));
+    }
+    if let Some(duration) = fields.duration {
+        members.push(format!("\"duration_ms\": {}", duration.as_millis()));
+    }
+    format!("{{{}}}", members.join(", "))
+}
+
+/// A log file which, once it grows past its maximum size, is renamed with
+/// the suffix `.1`, shifting older files to `.2` and so on up to `keep` of
+/// them, and begun again. Rotation happens on flush, between lines.
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    keep: usize,
+}
+
+impl LogFile {
+    /// Open `path` for appending, creating it if need be.
+    pub fn open<P: Into<PathBuf>>(path: P, max_size: Option<u64>, keep: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    /// The path of the `n`th older file.
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        match self.max_size {
+            Some(max) if self.size >= max => self.rotate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A size written like `512`, `64K`, `10M`, or `1G`, in bytes.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let n: u64 = s[..split].parse().ok()?;
+    let unit = match s[split..].trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    n.checked_mul(unit)
+}
+
+/// The syslog severity of a level.
+#[cfg(unix)]
+fn severity(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    }
+}
+
+/// A logger sending each record to the local syslog daemon through
+/// `/dev/log`, with the user facility and the severity of its level.
+#[cfg(unix)]
+pub struct Syslog(UnixDatagram);
+
+#[cfg(unix)]
+impl Syslog {
+    pub fn connect() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect("/dev/log")?;
+        Ok(Syslog(socket))
+    }
+}
+
+#[cfg(unix)]
+impl log::Log for Syslog {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        // The user facility is 1, shifted past the three bits of severity.
+        let line = format!(
+            "<{}>schemato[{}]: {}",
+            8 + severity(record.level()),
+            std::process::id(),
+            redact(&record.args().to_string())
+        );
+        let _ = self.0.send(line.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+/// A logger sending each record to the systemd journal with the priority of
+/// its level, and the current thread's [`Fields`] as `SCHEMATO_DATABASE`,
+/// `SCHEMATO_VERSION`, and `SCHEMATO_DURATION_MS` where they are known.
+#[cfg(unix)]
+pub struct Journald(UnixDatagram);
+
+#[cfg(unix)]
+impl Journald {
+    pub fn connect() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect("/run/systemd/journal/socket")?;
+        Ok(Journald(socket))
+    }
+}
+
+/// Append a field in the journal's native protocol: `KEY=value` on a line,
+/// or for a value spanning lines, the key on its own line followed by the
+/// value's length as a little-endian 64-bit integer, the value, and a line
+/// break.
+#[cfg(unix)]
+fn journal_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+#[cfg(unix)]
+impl log::Log for Journald {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let fields = current();
+        let mut buf = Vec::new();
+        journal_field(&mut buf, "MESSAGE", &redact(&record.args().to_string()));
+        journal_field(&mut buf, "PRIORITY", &severity(record.level()).to_string());
+        journal_field(&mut buf, "SYSLOG_IDENTIFIER", "schemato");
+        if let Some(ref database) = fields.database {
+            journal_field(&mut buf, "SCHEMATO_DATABASE", database);
+        }
+        if let Some(version) = fields.version {
+            journal_field(&mut buf, "SCHEMATO_VERSION", &version.to_string());
+        }
+        if let Some(duration) = fields.duration {
+            journal_field(
+                &mut buf,
+                "SCHEMATO_DURATION_MS",
+                &duration.as_millis().to_string(),
+            );
+        }
+        let _ = self.0.send(&buf);
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redact;
+
+    #[test]
+    fn leaves_short_passwords_elsewhere_in_the_line() {
+        // With `-P 1`, nothing here is the password.
+        let line = "found version 1 in 0001_t.sql";
+        assert_eq!(redact(line), line);
+    }
+
+    #[test]
+    fn redacts_uri_passwords() {
+        assert_eq!(
+            redact("connecting to postgres://app:1@db:5432/app"),
+            "connecting to postgres://app:********@db:5432/app"
+        );
+        assert_eq!(redact("postgres://app@db/app"), "postgres://app@db/app");
+    }
+
+    #[test]
+    fn redacts_sql_passwords() {
+        assert_eq!(
+            redact("CREATE ROLE app LOGIN PASSWORD '1'"),
+            "CREATE ROLE app LOGIN PASSWORD '********'"
+        );
+    }
+
+    #[test]
+    fn redacts_credential_fields() {
+        assert_eq!(
+            redact("host=db password=1 user=app"),
+            "host=db password=******** user=app"
+        );
+        assert_eq!(
+            redact(r#"{"access_token": "ab\"c", "expires_in": 3599}"#),
+            r#"{"access_token": "********", "expires_in": 3599}"#
+        );
+        assert_eq!(
+            redact("X-Vault-Token: s.1 sent"),
+            "X-Vault-Token: ******** sent"
+        );
+        assert_eq!(
+            redact("?client_secret=1&scope=x"),
+            "?client_secret=********&scope=x"
+        );
+    }
+
+    #[test]
+    fn leaves_mentions_of_passwords() {
+        let line = "password authentication failed for user \"app\"";
+        assert_eq!(redact(line), line);
+        let line = "failed reading the password from descriptor 3: bad fd";
+        assert_eq!(redact(line), line);
+        let line = "tokens: 3";
+        assert_eq!(redact(line), line);
+    }
+}
@@ -0,0 +1,123 @@
+//! Prometheus metrics describing the latest run against a database, for a
+//! Pushgateway or node_exporter's textfile collector.
+
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::http;
+use crate::summary::Summary;
+
+/// The summary in the Prometheus text exposition format. Every metric is
+/// labelled with the database.
+pub fn render(summary: &Summary) -> String {
+    let database = label(summary.database.as_deref().unwrap_or(""));
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, samples: Vec<(String, String)>| {
+        out += &format!("# HELP schemato_{} {}\n", name, help);
+        out += &format!("# TYPE schemato_{} gauge\n", name);
+        for (labels, value) in samples {
+            out += &format!(
+                "schemato_{}{{database=\"{}\"{}}} {}\n",
+                name, database, labels, value
+            );
+        }
+    };
+    let one = |value: String| vec![(String::new(), value)];
+
+    let finished = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    gauge(
+        "last_run_timestamp_seconds",
+        "When the latest run finished.",
+        one(finished.to_string()),
+    );
+    gauge(
+        "last_run_success",
+        "Whether the latest run succeeded.",
+        one((summary.outcome.is_ok() as u8).to_string()),
+    );
+    gauge(
+        "last_run_duration_seconds",
+        "How long the latest run took.",
+        one(seconds(summary.duration)),
+    );
+    gauge(
+        "last_run_files",
+        "The files applied, reverted, skipped, and failed by the latest run.",
+        vec![
+            ("applied", summary.applied.len()),
+            ("reverted", summary.reverted.len()),
+            ("skipped", summary.skipped.len()),
+            ("failed", summary.failed.len()),
+        ]
+        .into_iter()
+        .map(|(result, n)| (format!(",result=\"{}\"", result), n.to_string()))
+        .collect(),
+    );
+    if let Some(version) = summary.version {
+        gauge(
+            "schema_version",
+            "The latest version installed.",
+            one(version.to_string()),
+        );
+    }
+    let files: Vec<_> = summary
+        .applied
+        .iter()
+        .map(|t| ("applied", t))
+        .chain(summary.reverted.iter().map(|t| ("reverted", t)))
+        .map(|(result, t)| {
+            (
+                format!(",file=\"{}\",result=\"{}\"", label(&t.path), result),
+                seconds(t.duration),
+            )
+        })
+        .collect();
+    if !files.is_empty() {
+        gauge(
+            "file_duration_seconds",
+            "How long each file of the latest run took.",
+            files,
+        );
+    }
+    out
+}
+
+fn seconds(d: std::time::Duration) -> String {
+    format!("{:.3}", d.as_secs_f64())
+}
+
+/// A label value with its backslashes, quotes, and line breaks escaped.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Replace the metrics of the database's group on the Pushgateway at `url`.
+pub fn push(url: &str, summary: &Summary) -> io::Result<()> {
+    let database = summary.database.as_deref().unwrap_or("unknown");
+    let url = format!(
+        "{}/metrics/job/schemato/database/{}",
+        url.trim_end_matches('/'),
+        http::encode(database)
+    );
+    http::send(
+        "PUT",
+        &url,
+        "text/plain; version=0.0.4",
+        render(summary).as_bytes(),
+    )
+}
+
+/// Write the metrics to `path` for node_exporter's textfile collector,
+/// through a temporary file renamed into place so that it never reads half
+/// of them.
+pub fn write_textfile(path: &str, summary: &Summary) -> io::Result<()> {
+    let tmp = format!("{}.{}.tmp", path, std::process::id());
+    fs::write(&tmp, render(summary))?;
+    fs::rename(&tmp, path)
+}
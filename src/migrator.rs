@@ -0,0 +1,1653 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use postgres::{Connection, GenericConnection};
+
+use crate::catalog;
+use crate::config::{Config, ExportFormat, ImportSource, OutOfOrder, SnapshotFormat};
+use crate::db::{self, VersionRecord};
+use crate::error::{Error, Result};
+use crate::events::{self, Event};
+use crate::export;
+use crate::hooks;
+use crate::import;
+use crate::json;
+use crate::logging;
+use crate::schemata::{
+    self, checksum_like, checksum_matches, checksum_with, parse_directives, EmbeddedFile,
+    Migration, Repeatable,
+};
+use crate::signatures;
+use crate::tracking;
+
+/// A single change to be made to the database by `up` or `down`.
+#[derive(Debug)]
+pub enum Step<'m> {
+    Apply(&'m Migration),
+    Revert(&'m Migration),
+    /// Apply a repeatable file that is new or has changed.
+    Repeat(&'m Repeatable),
+    /// Apply a seed file that is new or has changed.
+    Seed(&'m Repeatable),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Installed, with a matching file.
+    Applied,
+    /// Has a file but is not installed.
+    Pending,
+    /// Installed, but its file is gone.
+    Missing,
+    /// The last attempt to apply it failed.
+    Failed,
+}
+
+/// The state of a single version, as reported by [`Migrator::status`].
+#[derive(Clone, Debug)]
+pub struct VersionStatus {
+    pub version: i64,
+    pub state: State,
+    pub applied: Option<DateTime<Utc>>,
+    pub path: Option<String>,
+    /// Description from the file name, or from the record of a missing file.
+    pub description: Option<String>,
+}
+
+/// A disagreement between the installed versions and the schemata files, as
+/// reported by [`Migrator::verify`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Drift {
+    /// Installed, but its file is gone.
+    Missing(i64),
+    /// Installed from a file that has changed since, with the checksum
+    /// recorded when it was applied and the checksum of the file now.
+    Modified {
+        version: i64,
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    /// Has a file but is not installed, although a later version is.
+    Skipped(i64, String),
+    /// The last attempt to apply it failed.
+    Failed(i64),
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Drift::Missing(ver) => write!(f, "version {} is installed but has no file", ver),
+            Drift::Modified {
+                version,
+                ref path,
+                ref expected,
+                ref actual,
+            } => write!(
+                f,
+                "version {} in {} has changed since it was applied (checksum {}, expected {})",
+                version, path, actual, expected
+            ),
+            Drift::Skipped(ver, ref path) => write!(
+                f,
+                "version {} in {} is not installed but later versions are",
+                ver, path
+            ),
+            Drift::Failed(ver) => write!(f, "version {} failed when it was last applied", ver),
+        }
+    }
+}
+
+/// An object that differs between a database built by applying every
+/// schemata file at once and one built a version at a time, as reported by
+/// [`Migrator::consistency`]. Each side holds the statement that would
+/// create the object, if it exists there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub kind: String,
+    pub name: String,
+    pub fresh: Option<String>,
+    pub incremental: Option<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.fresh, &self.incremental) {
+            (Some(_), None) => write!(f, "{} {} is only in the fresh build", self.kind, self.name),
+            (None, Some(_)) => write!(
+                f,
+                "{} {} is only in the incremental build",
+                self.kind, self.name
+            ),
+            (fresh, incremental) => write!(
+                f,
+                "{} {} differs\n  fresh:       {}\n  incremental: {}",
+                self.kind,
+                self.name,
+                fresh.as_ref().map_or("", |s| s),
+                incremental.as_ref().map_or("", |s| s)
+            ),
+        }
+    }
+}
+
+/// An object that differs between the database and a snapshot of its schema,
+/// as reported by [`Migrator::diff`]. Each side holds the statement that
+/// would create the object, if it exists there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+    pub kind: String,
+    pub name: String,
+    pub snapshot: Option<String>,
+    pub database: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.snapshot, &self.database) {
+            (Some(_), None) => write!(
+                f,
+                "{} {} is in the snapshot but not the database",
+                self.kind, self.name
+            ),
+            (None, Some(_)) => write!(
+                f,
+                "{} {} is in the database but not the snapshot",
+                self.kind, self.name
+            ),
+            (snapshot, database) => write!(
+                f,
+                "{} {} differs\n  snapshot: {}\n  database: {}",
+                self.kind,
+                self.name,
+                snapshot.as_ref().map_or("", |s| s),
+                database.as_ref().map_or("", |s| s)
+            ),
+        }
+    }
+}
+
+/// A change to the recorded versions that leaves the schema itself alone, as
+/// made by [`Migrator::repair`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fix {
+    /// Record the current checksum of an installed version's file.
+    Checksum {
+        version: i64,
+        path: String,
+        checksum: String,
+    },
+    /// Record a version as installed.
+    Record {
+        version: i64,
+        path: String,
+        description: Option<String>,
+        checksum: String,
+    },
+    /// Remove the record of an installed version.
+    Forget(i64),
+}
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fix::Checksum {
+                version,
+                ref path,
+                ref checksum,
+            } => write!(
+                f,
+                "record checksum {} of {} for version {}",
+                checksum, path, version
+            ),
+            Fix::Record {
+                version, ref path, ..
+            } => write!(
+                f,
+                "record version {} from {} without running it",
+                version, path
+            ),
+            Fix::Forget(ver) => write!(f, "forget version {}", ver),
+        }
+    }
+}
+
+/// The kinds of repair made by [`Migrator::repair`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Repairs {
+    /// Record the current checksum of each installed file that has changed
+    /// or was applied before checksums were recorded.
+    pub checksums: bool,
+    /// Forget installed versions whose files are gone.
+    pub missing: bool,
+    /// Forget the records of versions that failed to apply.
+    pub failed: bool,
+}
+
+/// Applies and reverts the schemata found in the configured directory, or
+/// embedded in the binary.
+pub struct Migrator {
+    config: Config,
+    schemata: Vec<Migration>,
+    repeatables: Vec<Repeatable>,
+    seeds: Vec<Repeatable>,
+    callbacks: Vec<String>,
+    embedded: Option<&'static [EmbeddedFile]>,
+}
+
+impl Migrator {
+    pub fn new(config: Config) -> Migrator {
+        Migrator {
+            config,
+            schemata: Vec::new(),
+            repeatables: Vec::new(),
+            seeds: Vec::new(),
+            callbacks: Vec::new(),
+            embedded: None,
+        }
+    }
+
+    /// A migrator for schemata embedded at compile time with
+    /// [`embed_migrations!`](crate::embed_migrations). The schemata directory
+    /// in the configuration is ignored.
+    pub fn from_embedded(config: Config, files: &'static [EmbeddedFile]) -> Migrator {
+        Migrator {
+            config,
+            schemata: Vec::new(),
+            repeatables: Vec::new(),
+            seeds: Vec::new(),
+            callbacks: Vec::new(),
+            embedded: Some(files),
+        }
+    }
+
+    /// Load the schemata from the configured directory or the embedded files,
+    /// and the seeds from the seeds directory.
+    pub fn discover(mut self) -> Result<Migrator> {
+        let names = match self.embedded {
+            Some(files) => {
+                info!("loading embedded schemata");
+                files.iter().map(|f| f.0.to_string()).collect()
+            }
+            None => {
+                info!("loading schemata from {}", self.config.prefix);
+                schemata::list_sql_files(&self.config.prefix)?
+            }
+        };
+        self.repeatables = schemata::parse_repeatables(&names);
+        self.callbacks = schemata::parse_callbacks(&names);
+        self.schemata = schemata::parse_names(names)?;
+        self.seeds = schemata::load_seeds(&self.config.seeds_dir())?;
+        events::emit(Event::Discovered {
+            versions: self.schemata.len(),
+            repeatables: self.repeatables.len(),
+        });
+        Ok(self)
+    }
+
+    /// Read the SQL of one of the discovered files.
+    pub fn read(&self, path: &str) -> Result<String> {
+        match self.embedded {
+            Some(files) => files
+                .iter()
+                .find(|f| f.0 == path)
+                .map(|f| f.1.to_string())
+                .ok_or_else(|| Error::Inconsistent(format!("no embedded file {}", path))),
+            None => schemata::read(&self.config.prefix, path),
+        }
+    }
+
+    /// Read the SQL of one of the discovered seed files.
+    pub fn read_seed(&self, path: &str) -> Result<String> {
+        schemata::read(&self.config.seeds_dir(), path)
+    }
+
+    /// The checksum to record for a file, with the configured algorithm.
+    fn checksum(&self, d: &str) -> String {
+        checksum_with(self.config.checksum_algorithm, d.as_bytes())
+    }
+
+    /// Read a schemata file to run it, checking its signature.
+    fn read_signed(&self, path: &str) -> Result<String> {
+        let d = self.read(path)?;
+        signatures::verify(&self.config, self.signatures_dir(), path, &d)?;
+        Ok(d)
+    }
+
+    /// The directory holding the signatures of the schemata files, unless
+    /// they are embedded.
+    fn signatures_dir(&self) -> Option<&str> {
+        match self.embedded {
+            Some(_) => None,
+            None => Some(&self.config.prefix),
+        }
+    }
+
+    /// Read a seed file to run it, checking its signature.
+    fn read_signed_seed(&self, path: &str) -> Result<String> {
+        let d = self.read_seed(path)?;
+        signatures::verify(&self.config, Some(&self.config.seeds_dir()), path, &d)?;
+        Ok(d)
+    }
+
+    /// Splice in the files named by include directives and fill in the
+    /// `${NAME}` placeholders of files with the `substitute` directive, as is
+    /// done before running a file. Checksums are of the file before this is
+    /// done.
+    pub fn render(&self, path: &str, sql: &str) -> Result<String> {
+        let sql = self.include(sql, &mut vec![path.to_string()])?;
+        Ok(if parse_directives(path, &sql)?.substitute {
+            schemata::substitute(&sql, &self.config.vars)
+        } else {
+            sql
+        })
+    }
+
+    /// Replace each include directive with the contents of the file it
+    /// names, recursively. `stack` holds the files being included, to catch
+    /// files that include themselves.
+    fn include(&self, sql: &str, stack: &mut Vec<String>) -> Result<String> {
+        let mut out = String::with_capacity(sql.len());
+        for line in sql.split_inclusive('\n') {
+            let path = match schemata::include_of(line) {
+                Some(path) => path,
+                None => {
+                    out.push_str(line);
+                    continue;
+                }
+            };
+            if stack.iter().any(|p| p == path) {
+                return Err(Error::Inconsistent(format!(
+                    "include cycle: {} -> {}",
+                    stack.join(" -> "),
+                    path
+                )));
+            }
+            debug!("including {} in {}", path, stack.last().unwrap());
+            stack.push(path.to_string());
+            let included = self.read_signed(path)?;
+            out.push_str(&self.include(&included, stack)?);
+            if !out.ends_with('\n') {
+                out.push('\n');
+            }
+            stack.pop();
+        }
+        Ok(out)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The discovered schemata, sorted by version.
+    pub fn schemata(&self) -> &[Migration] {
+        &self.schemata
+    }
+
+    /// The discovered repeatable files, sorted by name.
+    pub fn repeatables(&self) -> &[Repeatable] {
+        &self.repeatables
+    }
+
+    /// The discovered seed files, sorted by name.
+    pub fn seeds(&self) -> &[Repeatable] {
+        &self.seeds
+    }
+
+    /// Apply every schemata file that has not yet been installed, returning
+    /// the number applied.
+    pub fn apply_all(&self) -> Result<usize> {
+        self.up(None)
+    }
+
+    /// Apply every schemata file that has not yet been installed, or bring
+    /// the database to exactly the target version if one is given, then
+    /// apply any repeatable files that have changed. The database and the
+    /// schemato schema are created if necessary. Returns the number of
+    /// files applied or reverted.
+    pub fn up(&self, target: Option<i64>) -> Result<usize> {
+        hooks::around(&self.config, "up", || {
+            if self.config.shadow {
+                self.shadow(target)?;
+            }
+            self.bring_up(target)
+        })
+    }
+
+    /// Apply the schemata from scratch to a new database beside the target,
+    /// dropping it afterwards, to prove that they build a database by
+    /// themselves before they are applied to the target.
+    fn shadow(&self, target: Option<i64>) -> Result<()> {
+        let shadow = self.scratch("shadow");
+        info!("applying the schemata to {} first", shadow.config.db_name);
+        let result = events::mute(|| shadow.bring_up(target));
+        if let Err(e) = db::drop_database(&shadow.config) {
+            warn!("failed dropping {}: {}", shadow.config.db_name, e);
+        }
+        match result {
+            Ok(_) => {
+                info!("the schemata built {}", shadow.config.db_name);
+                Ok(())
+            }
+            Err(e) => Err(Error::Inconsistent(format!(
+                "not applying to {}, as the schemata failed to build a new database: {}",
+                self.config.db_name, e
+            ))),
+        }
+    }
+
+    /// A migrator for a new database beside the target, named after it with
+    /// the label and the process ID, which has no roles or grants to make.
+    fn scratch(&self, label: &str) -> Migrator {
+        let suffix = format!("_{}_{}", label, std::process::id());
+        let mut name = self.config.db_name.clone();
+        while name.len() + suffix.len() > db::MAX_IDENT {
+            name.pop();
+        }
+        let mut config = self.config.clone();
+        config.db_name = name + &suffix;
+        config.no_create_db = false;
+        config.shadow = false;
+        config.roles.clear();
+        config.grants.clear();
+        Migrator {
+            config,
+            schemata: self.schemata.clone(),
+            repeatables: self.repeatables.clone(),
+            seeds: Vec::new(),
+            callbacks: self.callbacks.clone(),
+            embedded: self.embedded,
+        }
+    }
+
+    /// Build two new databases beside the target, one by applying every
+    /// schemata file at once and one by applying them a version at a time
+    /// with the repeatable files after each, as successive deployments would,
+    /// then compare their schemas. Both are dropped afterwards. Returns the
+    /// objects that differ, so none when the schemata build the same schema
+    /// however they are deployed.
+    pub fn consistency(&self) -> Result<Vec<Divergence>> {
+        let fresh = self.scratch("fresh");
+        let steps = self.scratch("steps");
+        let result = self.build_both(&fresh, &steps);
+        for scratch in &[&fresh, &steps] {
+            if let Err(e) = db::drop_database(&scratch.config) {
+                warn!("failed dropping {}: {}", scratch.config.db_name, e);
+            }
+        }
+        let (a, b) = result?;
+
+        Ok(catalog::compare(&a, &b)
+            .into_iter()
+            .map(|(x, y)| {
+                let object = x.or(y).unwrap();
+                Divergence {
+                    kind: object.kind.clone(),
+                    name: object.name.clone(),
+                    fresh: x.map(|o| o.create.clone()),
+                    incremental: y.map(|o| o.create.clone()),
+                }
+            })
+            .collect())
+    }
+
+    /// Describe the schema of the database, as it stands after the schemata
+    /// applied to it, in the format given, to be kept beside the schemata
+    /// files. Sorted by kind and name, so that it changes only where the
+    /// schema does.
+    pub fn snapshot(&self, format: SnapshotFormat) -> Result<String> {
+        let cfg = &self.config;
+        info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+        let conn = db::connect_locked(cfg)?;
+        let objects = catalog::describe(&conn, cfg)?;
+        db::finish(conn)?;
+        info!("described {} objects", objects.len());
+        Ok(match format {
+            SnapshotFormat::Sql => catalog::to_sql(&cfg.db_name, &objects),
+            SnapshotFormat::Json => catalog::to_json(&objects),
+        })
+    }
+
+    /// Compare the schema of the database with a snapshot written by
+    /// [`snapshot`](Migrator::snapshot), to find changes made to either
+    /// other than by the schemata. Returns the objects that differ.
+    pub fn diff(&self, path: &str) -> Result<Vec<Difference>> {
+        let (snapshot, database) = self.compare_snapshot(path)?;
+        Ok(catalog::compare(&snapshot, &database)
+            .into_iter()
+            .map(|(x, y)| {
+                let object = x.or(y).unwrap();
+                Difference {
+                    kind: object.kind.clone(),
+                    name: object.name.clone(),
+                    snapshot: x.map(|o| o.create.clone()),
+                    database: y.map(|o| o.create.clone()),
+                }
+            })
+            .collect())
+    }
+
+    /// Write the statements that bring a database matching the snapshot to
+    /// the schema of this one into the next schemata file, named for the
+    /// description, as a starting point for capturing changes made by hand.
+    /// Returns the file created, or nothing if they already match.
+    pub fn emit_migration(&self, path: &str, description: &str) -> Result<Option<String>> {
+        let (snapshot, database) = self.compare_snapshot(path)?;
+        let statements = catalog::reconcile(&snapshot, &database);
+        if statements.is_empty() {
+            return Ok(None);
+        }
+        let created = schemata::create_next(&self.config.prefix, description, false, false)?;
+        let file = &created[0];
+        let mut contents = std::fs::read_to_string(file)
+            .map_err(|e| Error::Io(format!("failed reading {}", file), e))?;
+        contents.push_str(&format!(
+            "-- Generated by `schemato diff` from {}; review before applying.\n\n",
+            path
+        ));
+        contents.push_str(&statements.join("\n\n"));
+        contents.push('\n');
+        std::fs::write(file, contents)
+            .map_err(|e| Error::Io(format!("failed writing {}", file), e))?;
+        Ok(Some(file.clone()))
+    }
+
+    /// The objects of the snapshot at the path and of the database.
+    fn compare_snapshot(&self, path: &str) -> Result<(Vec<catalog::Object>, Vec<catalog::Object>)> {
+        let cfg = &self.config;
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Io(format!("failed reading {}", path), e))?;
+        let snapshot = catalog::parse(&text)
+            .map_err(|e| Error::Config(format!("bad snapshot {}: {}", path, e)))?;
+        info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+        let conn = db::connect_locked(cfg)?;
+        let database = catalog::describe(&conn, cfg)?;
+        db::finish(conn)?;
+        Ok((snapshot, database))
+    }
+
+    /// Build the fresh and the incremental databases for
+    /// [`consistency`](Migrator::consistency) and describe them.
+    fn build_both(
+        &self,
+        fresh: &Migrator,
+        steps: &Migrator,
+    ) -> Result<(Vec<catalog::Object>, Vec<catalog::Object>)> {
+        info!("applying the schemata to {} at once", fresh.config.db_name);
+        fresh.bring_up(None)?;
+        info!(
+            "applying the schemata to {} a version at a time",
+            steps.config.db_name
+        );
+        for m in &self.schemata {
+            steps.bring_up(Some(m.version))?;
+        }
+        if self.schemata.is_empty() {
+            steps.bring_up(None)?;
+        }
+
+        let mut described = Vec::new();
+        for scratch in &[fresh, steps] {
+            let conn = db::connect_locked(&scratch.config)?;
+            described.push(catalog::describe(&conn, &scratch.config)?);
+            db::finish(conn)?;
+        }
+        let b = described.pop().unwrap();
+        let a = described.pop().unwrap();
+        Ok((a, b))
+    }
+
+    /// Do what `up` would in one transaction, then check any deferred
+    /// constraints and roll it all back, leaving the database as it was, as
+    /// proof that the pending files apply to it. The database must exist.
+    /// Returns the number of files that were applied.
+    pub fn test(&self, target: Option<i64>) -> Result<usize> {
+        let cfg = &self.config;
+        info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+        let conn = db::connect_locked(cfg)?;
+        let err = |e| Error::Sql("failed testing the pending changes".to_string(), e);
+        let t = conn.transaction().map_err(err)?;
+        let exists = tracking::exists(&t, cfg)?;
+        tracking::upgrade(&t, cfg, exists)?;
+        tracking::start_track(&t, cfg)?;
+        let installed = db::load_installed(&t, cfg)?;
+        let repeated = db::load_repeated(&t, cfg, &cfg.repeatables_table())?;
+
+        let plan = self.plan_up_from(&installed, &repeated, target)?;
+        let outside = self.no_transaction_files(&plan)?;
+        if !outside.is_empty() {
+            return Err(Error::Config(format!(
+                "cannot test in a transaction the no-transaction files {}",
+                outside.join(", ")
+            )));
+        }
+        let changed = self.run(&t, &plan)?;
+        t.batch_execute("SET CONSTRAINTS ALL IMMEDIATE")
+            .map_err(|e| Error::Sql("failed checking deferred constraints".to_string(), e))?;
+
+        t.set_rollback();
+        t.finish().map_err(err)?;
+        db::finish(conn)?;
+        info!("applied {} changes and rolled them back", changed);
+        Ok(changed)
+    }
+
+    /// The files of a plan with the `no-transaction` directive.
+    fn no_transaction_files(&self, plan: &[Step]) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        for step in plan {
+            let (path, d) = match *step {
+                Step::Apply(m) => (&m.path, self.read_signed(&m.path)?),
+                Step::Revert(m) => {
+                    let path = m.down_path.as_ref().unwrap();
+                    (path, self.read_signed(path)?)
+                }
+                Step::Repeat(r) => (&r.path, self.read_signed(&r.path)?),
+                Step::Seed(s) => (&s.path, self.read_signed_seed(&s.path)?),
+            };
+            if parse_directives(path, &d)?.no_transaction {
+                files.push(path.clone());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Drop the database, ending the sessions connected to it, then create it
+    /// again and apply every schemata file, as when starting development
+    /// afresh. Refused when a host matches one of the protected patterns.
+    /// Returns the number of files applied.
+    pub fn reset(&self) -> Result<usize> {
+        hooks::around(&self.config, "reset", || {
+            if self.config.no_create_db {
+                return Err(Error::Config(
+                    "a database dropped with --no-create-db could not be created again".to_string(),
+                ));
+            }
+            db::check_unprotected(&self.config)?;
+            db::drop_database(&self.config)?;
+            self.bring_up(None)
+        })
+    }
+
+    /// Drop every schema the connecting role owns, the tracking schema among
+    /// them, and everything in the `public` schema but what extensions
+    /// installed, leaving the database as if new, as between test runs.
+    /// Refused when a host matches one of the protected patterns. Returns the
+    /// number of schemas and objects dropped.
+    pub fn clean(&self) -> Result<usize> {
+        hooks::around(&self.config, "clean", || {
+            let cfg = &self.config;
+            db::check_unprotected(cfg)?;
+            info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+            let conn = db::connect_locked(cfg)?;
+            let dropped = db::clean(&conn, cfg)?;
+
+            db::finish(conn)?;
+            info!("dropped {} schemas and objects", dropped);
+            Ok(dropped)
+        })
+    }
+
+    fn bring_up(&self, target: Option<i64>) -> Result<usize> {
+        db::prepare_database(&self.config)?;
+        let conn = db::connect_database(&self.config)?;
+        let installed = db::load_installed(&conn, &self.config)?;
+        let repeated = db::load_repeated(&conn, &self.config, &self.config.repeatables_table())?;
+
+        let changed = self.run(&conn, &self.plan_up_from(&installed, &repeated, target)?)?;
+        db::grant(&conn, &self.config)?;
+
+        db::finish(conn)?;
+        info!("complete");
+        Ok(changed)
+    }
+
+    /// Revert the most recently applied version, or every version above the
+    /// target if one is given. Returns the number of versions reverted.
+    pub fn down(&self, target: Option<i64>) -> Result<usize> {
+        hooks::around(&self.config, "down", || {
+            let cfg = &self.config;
+            info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+            let conn = db::connect_existing(cfg)?;
+            let installed = db::load_installed(&conn, &self.config)?;
+
+            let changed = self.run(&conn, &self.plan_down_from(&installed, target)?)?;
+
+            db::finish(conn)?;
+            info!("complete");
+            Ok(changed)
+        })
+    }
+
+    /// Revert the most recently applied version and apply it again from its
+    /// current file, as when iterating on it during development. Returns
+    /// the number of steps taken.
+    pub fn redo(&self) -> Result<usize> {
+        hooks::around(&self.config, "redo", || {
+            let cfg = &self.config;
+            info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+            let conn = db::connect_existing(cfg)?;
+            let installed = db::load_installed(&conn, &self.config)?;
+
+            let changed = self.run(&conn, &self.plan_redo_from(&installed)?)?;
+            db::grant(&conn, &self.config)?;
+
+            db::finish(conn)?;
+            info!("complete");
+            Ok(changed)
+        })
+    }
+
+    /// The steps `up` would take, determined without changing anything.
+    pub fn plan_up(&self, target: Option<i64>) -> Result<Vec<Step<'_>>> {
+        let (installed, repeated) = match self.peek()? {
+            Some(conn) => {
+                let installed = db::load_installed(&conn, &self.config)?;
+                let repeated =
+                    db::load_repeated(&conn, &self.config, &self.config.repeatables_table())?;
+                db::finish(conn)?;
+                (installed, repeated)
+            }
+            None => Default::default(),
+        };
+        self.plan_up_from(&installed, &repeated, target)
+    }
+
+    /// Apply the seed files that are new or have changed since they were
+    /// last applied. Every versioned and repeatable file must have been
+    /// applied first. Returns the number of seed files applied.
+    pub fn seed(&self) -> Result<usize> {
+        hooks::around(&self.config, "seed", || {
+            let cfg = &self.config;
+            info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+            let conn = db::connect_existing(cfg)?;
+            let installed = db::load_installed(&conn, cfg)?;
+            let repeated = db::load_repeated(&conn, cfg, &cfg.repeatables_table())?;
+            let seeded = db::load_repeated(&conn, cfg, &cfg.seeds_table())?;
+
+            let changed = self.run(&conn, &self.plan_seed_from(&installed, &repeated, &seeded)?)?;
+            db::grant(&conn, cfg)?;
+
+            db::finish(conn)?;
+            info!("complete");
+            Ok(changed)
+        })
+    }
+
+    /// The steps `seed` would take, determined without changing anything.
+    pub fn plan_seed(&self) -> Result<Vec<Step<'_>>> {
+        let cfg = &self.config;
+        let conn = self.peek()?.ok_or_else(|| {
+            Error::Inconsistent(format!(
+                "{}.{} does not exist",
+                cfg.db_name,
+                cfg.versions_table()
+            ))
+        })?;
+        let installed = db::load_installed(&conn, cfg)?;
+        let repeated = db::load_repeated(&conn, cfg, &cfg.repeatables_table())?;
+        let seeded = db::load_repeated(&conn, cfg, &cfg.seeds_table())?;
+        db::finish(conn)?;
+        self.plan_seed_from(&installed, &repeated, &seeded)
+    }
+
+    /// The steps `down` would take, determined without changing anything.
+    pub fn plan_down(&self, target: Option<i64>) -> Result<Vec<Step<'_>>> {
+        let installed = self.peek_installed()?;
+        self.plan_down_from(&installed, target)
+    }
+
+    /// The steps `redo` would take, determined without changing anything.
+    pub fn plan_redo(&self) -> Result<Vec<Step<'_>>> {
+        let installed = self.peek_installed()?;
+        self.plan_redo_from(&installed)
+    }
+
+    /// Correct the recorded versions to agree with the schemata files, without
+    /// running any of them.
+    pub fn repair(&self, repairs: Repairs) -> Result<()> {
+        let cfg = &self.config;
+        info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+        let conn = db::connect_existing(cfg)?;
+        let history = db::load_history(&conn, cfg)?;
+
+        self.fix(&conn, &self.plan_repair_from(&history, repairs))?;
+
+        db::finish(conn)?;
+        info!("complete");
+        Ok(())
+    }
+
+    /// Record every version up to and including `version` as installed
+    /// without running it, creating the schemato schema if necessary, so
+    /// that a database created by other means is brought under management.
+    pub fn baseline(&self, version: i64) -> Result<()> {
+        let cfg = &self.config;
+        info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+        let conn = db::connect_database(cfg)?;
+        let installed = db::load_installed(&conn, cfg)?;
+
+        let mut fixes = Vec::new();
+        for m in self.schemata.iter().filter(|m| m.version <= version) {
+            if installed.contains_key(&m.version) {
+                info!("installed: {}", m.version);
+            } else {
+                fixes.push(self.record(m)?);
+            }
+        }
+        self.fix(&conn, &fixes)?;
+
+        db::finish(conn)?;
+        info!("complete");
+        Ok(())
+    }
+
+    /// Record a single version as installed without running it, as when it
+    /// was applied by hand.
+    pub fn mark(&self, version: i64) -> Result<()> {
+        let m = self
+            .schemata
+            .iter()
+            .find(|m| m.version == version)
+            .ok_or_else(|| {
+                Error::Inconsistent(format!("no schemata file for version {}", version))
+            })?;
+        let cfg = &self.config;
+        info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+        let conn = db::connect_database(cfg)?;
+        if db::load_installed(&conn, cfg)?.contains_key(&version) {
+            return Err(Error::Inconsistent(format!(
+                "version {} is already installed",
+                version
+            )));
+        }
+
+        self.fix(&conn, &[self.record(m)?])?;
+
+        db::finish(conn)?;
+        info!("complete");
+        Ok(())
+    }
+
+    /// Record the versions another migration tool applied, read from its
+    /// history in the table given or the tool's usual one, so that schemato
+    /// can take over the database. Versions are matched to the schemata files
+    /// by number, or as given by a mapping file, and recorded with the files'
+    /// checksums; a baseline, or golang-migrate's latest version, also
+    /// records every file before it. Returns the number of versions recorded.
+    pub fn import(
+        &self,
+        source: ImportSource,
+        table: Option<&str>,
+        mapping: Option<&str>,
+    ) -> Result<usize> {
+        let mapping = match mapping {
+            Some(path) => import::read_mapping(path)?,
+            None => BTreeMap::new(),
+        };
+        let cfg = &self.config;
+        let table = table.unwrap_or_else(|| import::default_table(source));
+        info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+        let conn = db::connect_database(cfg)?;
+        let applied = import::read(&conn, cfg, source, table)?;
+        let installed = db::load_installed(&conn, cfg)?;
+
+        // Later entries for a version take precedence over earlier ones.
+        let mut versions: BTreeMap<i64, &import::Applied> = BTreeMap::new();
+        for a in &applied {
+            let version = match mapping.get(&a.version) {
+                Some(&version) => version,
+                None => a.version.parse().map_err(|_| {
+                    Error::Config(format!(
+                        "{} version {} is not a whole number, as schemato versions must be; \
+                         map it to one with --mapping",
+                        source, a.version
+                    ))
+                })?,
+            };
+            if a.undo {
+                versions.remove(&version);
+            } else if a.success {
+                versions.insert(version, a);
+            } else {
+                warn!("not importing version {}, which failed", version);
+            }
+        }
+
+        let mut records: BTreeMap<i64, VersionRecord> = BTreeMap::new();
+        for (&version, a) in &versions {
+            if installed.contains_key(&version) {
+                info!("installed: {}", version);
+                continue;
+            }
+            match self.schemata.iter().find(|m| m.version == version) {
+                Some(m) => {
+                    records.insert(version, self.imported(m, a)?);
+                }
+                None if a.baseline => info!("no schemata file for baseline {}", version),
+                None => {
+                    return Err(Error::Inconsistent(format!(
+                        "{} version {}{} has no schemata file; map it to one with --mapping",
+                        source,
+                        a.version,
+                        a.script
+                            .as_ref()
+                            .map_or(String::new(), |s| format!(" from {}", s))
+                    )))
+                }
+            }
+            if a.baseline {
+                for m in self.schemata.iter().filter(|m| m.version < version) {
+                    let known = versions.contains_key(&m.version)
+                        || installed.contains_key(&m.version)
+                        || records.contains_key(&m.version);
+                    if !known {
+                        records.insert(m.version, self.imported(m, a)?);
+                    }
+                }
+            }
+        }
+        for r in records.values() {
+            info!(
+                "record version {} from {} as applied by {}",
+                r.version,
+                r.path.as_deref().unwrap_or("-"),
+                source
+            );
+        }
+
+        let records: Vec<VersionRecord> = records.into_iter().map(|r| r.1).collect();
+        db::import(&conn, &records, cfg)?;
+        db::finish(conn)?;
+        info!("imported {} versions", records.len());
+        Ok(records.len())
+    }
+
+    /// The record of a schemata file applied by another tool, with the
+    /// file's checksum and the tool's account of applying it.
+    fn imported(&self, m: &Migration, a: &import::Applied) -> Result<VersionRecord> {
+        let d = self.read(&m.path)?;
+        let dir = parse_directives(&m.path, &d)?;
+        Ok(VersionRecord {
+            version: m.version,
+            applied: a.applied.unwrap_or_else(Utc::now),
+            checksum: Some(self.checksum(&d)),
+            path: Some(m.path.clone()),
+            description: dir
+                .description
+                .or_else(|| m.description.clone())
+                .or_else(|| a.description.clone()),
+            duration: a
+                .duration_ms
+                .map(|ms| Duration::from_millis(ms.max(0) as u64)),
+            applied_by: a.applied_by.clone(),
+            client_host: None,
+            revision: None,
+            success: true,
+            out_of_order: false,
+            labels: BTreeMap::new(),
+        })
+    }
+
+    /// Remove the record of a single installed version without reverting it,
+    /// as when it was rolled back by hand.
+    pub fn unmark(&self, version: i64) -> Result<()> {
+        let cfg = &self.config;
+        info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+        let conn = db::connect_existing(cfg)?;
+        if version <= 0 || !db::load_installed(&conn, cfg)?.contains_key(&version) {
+            return Err(Error::Inconsistent(format!(
+                "version {} is not installed",
+                version
+            )));
+        }
+
+        self.fix(&conn, &[Fix::Forget(version)])?;
+
+        db::finish(conn)?;
+        info!("complete");
+        Ok(())
+    }
+
+    /// The changes `repair` would make, determined without changing anything.
+    pub fn plan_repair(&self, repairs: Repairs) -> Result<Vec<Fix>> {
+        let history = self.peek_history()?;
+        Ok(self.plan_repair_from(&history, repairs))
+    }
+
+    /// Every recorded version, including those that failed to apply.
+    pub fn history(&self) -> Result<Vec<VersionRecord>> {
+        let cfg = &self.config;
+        info!("connecting to {}/{}", cfg.uri_safe(), cfg.db_name);
+        let conn = db::connect_loop(cfg, false)?;
+
+        let history = if tracking::exists(&conn, cfg)? {
+            db::load_history(&conn, cfg)?
+        } else {
+            warn!("{}.{} does not exist", cfg.db_name, cfg.versions_table());
+            Vec::new()
+        };
+
+        db::finish(conn)?;
+        Ok(history)
+    }
+
+    /// Write every recorded attempt to apply a version of the track, with
+    /// when, how long, by whom, and from where, in the format given.
+    pub fn export(&self, format: ExportFormat) -> Result<String> {
+        let history = self.history()?;
+        Ok(match format {
+            ExportFormat::Json => export::to_json(&self.config, &history),
+            ExportFormat::Csv => export::to_csv(&self.config, &history),
+        })
+    }
+
+    /// Compare the schemata files against the installed versions.
+    pub fn status(&self) -> Result<Vec<VersionStatus>> {
+        let installed: BTreeMap<i64, VersionRecord> = self
+            .history()?
+            .into_iter()
+            .map(|r| (r.version, r))
+            .collect();
+
+        let files: BTreeMap<i64, &Migration> =
+            self.schemata.iter().map(|m| (m.version, m)).collect();
+        let versions: BTreeSet<i64> = files.keys().chain(installed.keys()).cloned().collect();
+
+        Ok(versions
+            .into_iter()
+            .map(|ver| {
+                let record = installed.get(&ver);
+                // Version 0 records the creation of the schemato schema and
+                // has no file.
+                let state = match record {
+                    Some(r) if !r.success => State::Failed,
+                    Some(_) if files.contains_key(&ver) || ver == 0 => State::Applied,
+                    Some(_) => State::Missing,
+                    None => State::Pending,
+                };
+                VersionStatus {
+                    version: ver,
+                    state,
+                    applied: record.map(|r| r.applied),
+                    path: files.get(&ver).map(|m| m.path.clone()),
+                    description: match files.get(&ver) {
+                        Some(m) => m.description.clone(),
+                        None => record.and_then(|r| r.description.clone()),
+                    },
+                }
+            })
+            .collect())
+    }
+
+    /// Check that every installed version has a file whose checksum matches
+    /// the one recorded for it, and that no version without a file
+    /// installed precedes one that is. Nothing is changed.
+    pub fn verify(&self) -> Result<Vec<Drift>> {
+        let history = self.peek_history()?;
+        let installed = db::installed(&history);
+        let mut drift: Vec<Drift> = self
+            .missing(&installed)
+            .into_iter()
+            .map(Drift::Missing)
+            .collect();
+        drift.extend(self.modified(&installed));
+        if let Some(&latest) = installed.keys().next_back() {
+            drift.extend(
+                self.schemata
+                    .iter()
+                    .filter(|m| m.version < latest && !installed.contains_key(&m.version))
+                    .map(|m| Drift::Skipped(m.version, m.path.clone())),
+            );
+        }
+        drift.extend(
+            history
+                .iter()
+                .filter(|r| !r.success)
+                .map(|r| Drift::Failed(r.version)),
+        );
+        Ok(drift)
+    }
+
+    fn plan_repair_from(&self, history: &[VersionRecord], repairs: Repairs) -> Vec<Fix> {
+        let installed = &db::installed(history);
+        let mut fixes = Vec::new();
+        if repairs.checksums {
+            for m in &self.schemata {
+                let recorded = match installed.get(&m.version) {
+                    Some(i) => i.checksum.as_ref(),
+                    None => continue,
+                };
+                match self
+                    .read(&m.path)
+                    .and_then(|d| Ok((checksum_matches(&m.path, recorded, d.as_bytes())?, d)))
+                {
+                    Ok((true, _)) => {}
+                    Ok((false, d)) => fixes.push(Fix::Checksum {
+                        version: m.version,
+                        path: m.path.clone(),
+                        checksum: self.checksum(&d),
+                    }),
+                    Err(e) => warn!("{}", e),
+                }
+            }
+        }
+        if repairs.missing {
+            fixes.extend(self.missing(installed).into_iter().map(Fix::Forget));
+        }
+        if repairs.failed {
+            fixes.extend(
+                history
+                    .iter()
+                    .filter(|r| !r.success)
+                    .map(|r| Fix::Forget(r.version)),
+            );
+        }
+        fixes
+    }
+
+    fn plan_up_from(
+        &self,
+        installed: &BTreeMap<i64, VersionRecord>,
+        repeated: &BTreeMap<String, String>,
+        target: Option<i64>,
+    ) -> Result<Vec<Step<'_>>> {
+        self.verify_checksums(installed)?;
+
+        let mut plan = match target {
+            Some(t) => self.plan_revert(installed, t)?,
+            None => Vec::new(),
+        };
+        // The latest version left installed once the reverts are done.
+        let latest = installed
+            .keys()
+            .rev()
+            .find(|&&v| target.is_none_or(|t| v <= t))
+            .cloned()
+            .unwrap_or(0);
+
+        let mut out_of_order = Vec::new();
+        for m in &self.schemata {
+            if installed.contains_key(&m.version) {
+                info!("installed: {}", m.version);
+            } else if target.is_some_and(|t| m.version > t) {
+                debug!("beyond target: {}", m.version);
+            } else if m.version < latest {
+                match self.config.out_of_order {
+                    OutOfOrder::Fail => out_of_order.push(m.version.to_string()),
+                    OutOfOrder::Skip => {
+                        warn!(
+                            "skipping version {}, which is older than installed version {}",
+                            m.version, latest
+                        );
+                        events::emit(Event::Skipped {
+                            version: Some(m.version),
+                            path: m.path.clone(),
+                            reason: format!("older than installed version {}", latest),
+                        });
+                    }
+                    OutOfOrder::Apply => {
+                        warn!(
+                            "version {} is older than installed version {} and will be applied out of order",
+                            m.version, latest
+                        );
+                        plan.push(Step::Apply(m));
+                    }
+                }
+            } else {
+                plan.push(Step::Apply(m));
+            }
+        }
+        if !out_of_order.is_empty() {
+            return Err(Error::Inconsistent(format!(
+                "pending versions {} are older than installed version {}",
+                out_of_order.join(", "),
+                latest
+            )));
+        }
+
+        for r in &self.repeatables {
+            let d = self.read(&r.path)?;
+            if checksum_matches(&r.path, repeated.get(&r.path), d.as_bytes())? {
+                debug!("unchanged: {}", r.path);
+            } else {
+                plan.push(Step::Repeat(r));
+            }
+        }
+
+        Ok(plan)
+    }
+
+    fn plan_seed_from(
+        &self,
+        installed: &BTreeMap<i64, VersionRecord>,
+        repeated: &BTreeMap<String, String>,
+        seeded: &BTreeMap<String, String>,
+    ) -> Result<Vec<Step<'_>>> {
+        let pending = self.plan_up_from(installed, repeated, None)?.len();
+        if pending > 0 {
+            return Err(Error::Inconsistent(format!(
+                "{} changes are pending: run up before seeding",
+                pending
+            )));
+        }
+
+        let mut plan = Vec::new();
+        if self.seeds.is_empty() {
+            warn!("no seeds found in {}", self.config.seeds_dir());
+        }
+        for s in &self.seeds {
+            let d = self.read_seed(&s.path)?;
+            if checksum_matches(&s.path, seeded.get(&s.path), d.as_bytes())? {
+                debug!("unchanged: {}", s.path);
+            } else {
+                plan.push(Step::Seed(s));
+            }
+        }
+        Ok(plan)
+    }
+
+    fn plan_down_from(
+        &self,
+        installed: &BTreeMap<i64, VersionRecord>,
+        target: Option<i64>,
+    ) -> Result<Vec<Step<'_>>> {
+        let target = match target {
+            Some(t) => t,
+            None => match installed.keys().rev().nth(1) {
+                Some(&v) => v,
+                None => 0,
+            },
+        };
+
+        let plan = self.plan_revert(installed, target)?;
+        if plan.is_empty() {
+            info!("no installed versions to roll back");
+        }
+        Ok(plan)
+    }
+
+    fn plan_redo_from(&self, installed: &BTreeMap<i64, VersionRecord>) -> Result<Vec<Step<'_>>> {
+        let mut plan = self.plan_down_from(installed, None)?;
+        if let Some(&Step::Revert(m)) = plan.first() {
+            plan.push(Step::Apply(m));
+        }
+        Ok(plan)
+    }
+
+    /// Plan the reversion of installed versions newer than the target,
+    /// newest first. Every required down file must exist before anything is
+    /// reverted.
+    fn plan_revert(
+        &self,
+        installed: &BTreeMap<i64, VersionRecord>,
+        target: i64,
+    ) -> Result<Vec<Step<'_>>> {
+        let mut plan = Vec::new();
+        for &ver in installed.keys().rev().take_while(|&&v| v > target && v > 0) {
+            let m = self
+                .schemata
+                .iter()
+                .find(|m| m.version == ver)
+                .ok_or_else(|| {
+                    Error::Inconsistent(format!("no schemata file for version {}", ver))
+                })?;
+            if m.down_path.is_none() {
+                return Err(Error::Inconsistent(format!(
+                    "no down file for version {}",
+                    ver
+                )));
+            }
+            plan.push(Step::Revert(m));
+        }
+        Ok(plan)
+    }
+
+    /// Compare each applied file against the checksum recorded when it was
+    /// applied. Versions applied before checksums were recorded are not
+    /// checked.
+    fn verify_checksums(&self, installed: &BTreeMap<i64, VersionRecord>) -> Result<()> {
+        let cfg = &self.config;
+        let modified = self.modified(installed);
+        for drift in &modified {
+            if cfg.allow_modified {
+                warn!("{}", drift);
+            } else {
+                error!("{}", drift);
+            }
+        }
+        if !modified.is_empty() && !cfg.allow_modified {
+            return Err(Error::Modified(modified.len()));
+        }
+        Ok(())
+    }
+
+    /// The installed versions whose files are gone.
+    fn missing(&self, installed: &BTreeMap<i64, VersionRecord>) -> Vec<i64> {
+        installed
+            .keys()
+            .filter(|&&ver| ver > 0 && !self.schemata.iter().any(|m| m.version == ver))
+            .cloned()
+            .collect()
+    }
+
+    /// The applied files whose checksums differ from those recorded when they
+    /// were applied.
+    fn modified(&self, installed: &BTreeMap<i64, VersionRecord>) -> Vec<Drift> {
+        let mut modified = Vec::new();
+        for m in &self.schemata {
+            let expected = match installed.get(&m.version) {
+                Some(VersionRecord {
+                    checksum: Some(ref c),
+                    ..
+                }) => c,
+                _ => continue,
+            };
+            match self
+                .read(&m.path)
+                .and_then(|d| checksum_like(&m.path, expected, d.as_bytes()))
+            {
+                Ok(actual) => {
+                    if &actual != expected {
+                        modified.push(Drift::Modified {
+                            version: m.version,
+                            path: m.path.clone(),
+                            expected: expected.clone(),
+                            actual,
+                        });
+                    }
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+        modified
+    }
+
+    /// The fix recording a version as installed from its current file.
+    fn record(&self, m: &Migration) -> Result<Fix> {
+        let d = self.read(&m.path)?;
+        let dir = parse_directives(&m.path, &d)?;
+        Ok(Fix::Record {
+            version: m.version,
+            path: m.path.clone(),
+            description: dir.description.or_else(|| m.description.clone()),
+            checksum: self.checksum(&d),
+        })
+    }
+
+    fn fix(&self, conn: &Connection, fixes: &[Fix]) -> Result<()> {
+        for f in fixes {
+            info!("{}", f);
+        }
+        if fixes.is_empty() {
+            info!("nothing to change");
+            return Ok(());
+        }
+        db::fix(conn, fixes, &self.config)
+    }
+
+    /// Take each step of the plan, returning the number taken.
+    fn run(&self, conn: &dyn GenericConnection, plan: &[Step]) -> Result<usize> {
+        if plan.is_empty() {
+            self.installed(conn)?;
+            return Ok(0);
+        }
+        self.require_signatures(plan)?;
+        events::emit(Event::Planned { steps: plan.len() });
+        self.callback(conn, "beforeMigrate.sql")?;
+        let mut taken = Vec::new();
+        for step in plan {
+            let (version, path) = match *step {
+                Step::Apply(m) => (Some(m.version), m.path.clone()),
+                Step::Revert(m) => (Some(m.version), m.down_path.clone().unwrap()),
+                Step::Repeat(r) | Step::Seed(r) => (None, r.path.clone()),
+            };
+            let _fields = logging::scope(|f| f.version = version);
+            self.callback(conn, "beforeEach.sql")?;
+            events::emit(match *step {
+                Step::Revert(m) => Event::Reverting {
+                    version: m.version,
+                    path: path.clone(),
+                },
+                _ => Event::Applying {
+                    version,
+                    path: path.clone(),
+                },
+            });
+            let start = Instant::now();
+            match self.take(conn, step) {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.callback(conn, "afterEach.sql")?;
+                    continue;
+                }
+                Err(e) => {
+                    events::emit(Event::Failed {
+                        version,
+                        path,
+                        error: e.to_string(),
+                    });
+                    return Err(e);
+                }
+            }
+            let duration = start.elapsed();
+            {
+                let _fields = logging::scope(|f| f.duration = Some(duration));
+                let ms = duration.as_millis();
+                match *step {
+                    Step::Apply(m) => info!("applied version {} in {}ms", m.version, ms),
+                    Step::Revert(m) => info!("reverted version {} in {}ms", m.version, ms),
+                    Step::Repeat(_) | Step::Seed(_) => info!("applied {} in {}ms", path, ms),
+                }
+            }
+            events::emit(match *step {
+                Step::Revert(m) => Event::Reverted {
+                    version: m.version,
+                    path,
+                    duration,
+                },
+                _ => Event::Applied {
+                    version,
+                    path,
+                    duration,
+                },
+            });
+            self.callback(conn, "afterEach.sql")?;
+            taken.push(step);
+        }
+        self.callback(conn, "afterMigrate.sql")?;
+        self.installed(conn)?;
+        if !taken.is_empty() {
+            self.notify(conn, &taken)?;
+        }
+        Ok(taken.len())
+    }
+
+    /// Tell the sessions listening on the configured channel, if any, what
+    /// the run changed, as a JSON object with the `database`, `track`, the
+    /// latest `version` installed, and the files `applied` and `reverted`.
+    /// The files are left out if they would make the payload too long for
+    /// NOTIFY.
+    fn notify(&self, conn: &dyn GenericConnection, taken: &[&Step]) -> Result<()> {
+        let cfg = &self.config;
+        let channel = match cfg.notify_channel {
+            Some(ref channel) => channel,
+            None => return Ok(()),
+        };
+        let paths = |revert: bool| {
+            let paths: Vec<String> = taken
+                .iter()
+                .filter_map(|step| match **step {
+                    Step::Revert(m) if revert => m.down_path.as_deref().map(json::string),
+                    Step::Apply(m) if !revert => Some(json::string(&m.path)),
+                    Step::Repeat(r) | Step::Seed(r) if !revert => Some(json::string(&r.path)),
+                    _ => None,
+                })
+                .collect();
+            format!("[{}]", paths.join(", "))
+        };
+        let version = db::latest_version(conn, cfg)?;
+        let mut head = format!(
+            "{{\"database\": {}, \"track\": {}, \"version\": {}",
+            json::string(&cfg.db_name),
+            json::string(&cfg.track),
+            version.map_or("null".to_string(), |v| v.to_string())
+        );
+        if !cfg.labels.is_empty() {
+            head += &format!(", \"labels\": {}", json::object(&cfg.labels));
+        }
+        let mut payload = format!(
+            "{}, \"applied\": {}, \"reverted\": {}}}",
+            head,
+            paths(false),
+            paths(true)
+        );
+        // Postgres refuses payloads of 8000 bytes or more.
+        if payload.len() >= 8000 {
+            payload = format!("{}}}", head);
+        }
+        db::notify(conn, channel, &payload)
+    }
+
+    /// Report the latest installed version once a run is done, if anything
+    /// is listening.
+    fn installed(&self, conn: &dyn GenericConnection) -> Result<()> {
+        if events::listening() {
+            let version = db::latest_version(conn, &self.config)?;
+            events::emit(Event::Installed { version });
+        }
+        Ok(())
+    }
+
+    /// Refuse a plan with unsigned files before taking any of its steps, if
+    /// signatures are required. Each file is verified as it is run.
+    fn require_signatures(&self, plan: &[Step]) -> Result<()> {
+        let dir = self.signatures_dir();
+        for step in plan {
+            match *step {
+                Step::Apply(m) => signatures::require(&self.config, dir, &m.path)?,
+                Step::Revert(m) => {
+                    signatures::require(&self.config, dir, m.down_path.as_ref().unwrap())?
+                }
+                Step::Repeat(r) => signatures::require(&self.config, dir, &r.path)?,
+                Step::Seed(s) => {
+                    signatures::require(&self.config, Some(&self.config.seeds_dir()), &s.path)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a step of the plan, returning false if it was skipped.
+    fn take(&self, conn: &dyn GenericConnection, step: &Step) -> Result<bool> {
+        match *step {
+            Step::Apply(m) => {
+                info!("applying version {} from {}", m.version, m.path);
+                let d = match self.read_signed(&m.path) {
+                    Ok(d) => d,
+                    Err(e) if self.config.force => {
+                        warn!("skipping version {} due to error {}", m.version, e);
+                        events::emit(Event::Skipped {
+                            version: Some(m.version),
+                            path: m.path.clone(),
+                            reason: e.to_string(),
+                        });
+                        return Ok(false);
+                    }
+                    Err(e) => return Err(e),
+                };
+                let dir = parse_directives(&m.path, &d)?;
+                let sql = self.render(&m.path, &d)?;
+                db::apply(conn, m, &sql, &dir, &self.checksum(&d), &self.config)
+            }
+            Step::Revert(m) => {
+                let path = m.down_path.as_ref().unwrap();
+                info!("reverting version {} using {}", m.version, path);
+                let d = self.read_signed(path)?;
+                let dir = parse_directives(path, &d)?;
+                let sql = self.render(path, &d)?;
+                db::revert(conn, m.version, path, &sql, &dir, &self.config)?;
+                Ok(true)
+            }
+            Step::Repeat(r) => {
+                info!("applying {}", r.path);
+                let d = self.read_signed(&r.path)?;
+                let dir = parse_directives(&r.path, &d)?;
+                let sql = self.render(&r.path, &d)?;
+                let table = self.config.repeatables_table();
+                let sum = self.checksum(&d);
+                db::repeat(conn, &table, r, &sql, &dir, &sum, &self.config)
+            }
+            Step::Seed(s) => {
+                info!("seeding from {}", s.path);
+                let d = self.read_signed_seed(&s.path)?;
+                let dir = parse_directives(&s.path, &d)?;
+                let sql = self.render(&s.path, &d)?;
+                let table = self.config.seeds_table();
+                let sum = self.checksum(&d);
+                db::repeat(conn, &table, s, &sql, &dir, &sum, &self.config)
+            }
+        }
+    }
+
+    /// Run a callback file, if there is one, outside of any transaction.
+    fn callback(&self, conn: &dyn GenericConnection, name: &str) -> Result<()> {
+        if !self.callbacks.iter().any(|c| c == name) {
+            return Ok(());
+        }
+        debug!("running {}", name);
+        let sql = self.render(name, &self.read_signed(name)?)?;
+        conn.batch_execute(&sql)
+            .map_err(|e| Error::Sql(format!("failed running {}", name), e))
+    }
+
+    /// Load the installed versions without creating or locking anything, for
+    /// previewing changes. A missing database or schema has nothing
+    /// installed.
+    fn peek_installed(&self) -> Result<BTreeMap<i64, VersionRecord>> {
+        Ok(db::installed(&self.peek_history()?))
+    }
+
+    /// Load every recorded version without creating or locking anything.
+    fn peek_history(&self) -> Result<Vec<VersionRecord>> {
+        match self.peek()? {
+            Some(conn) => {
+                let history = db::load_history(&conn, &self.config)?;
+                db::finish(conn)?;
+                Ok(history)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Connect to the database without creating or locking anything, unless
+    /// the database or its tracking tables have yet to be created.
+    fn peek(&self) -> Result<Option<Connection>> {
+        let cfg = &self.config;
+        info!("connecting to {}", cfg.uri_safe());
+        let anon_conn = db::connect_loop(cfg, true)?;
+        let exists = db::database_exists(&anon_conn, cfg)?;
+        db::finish(anon_conn)?;
+        if !exists {
+            info!(
+                "database {} does not exist and would be created",
+                cfg.db_name
+            );
+            return Ok(None);
+        }
+
+        let conn = db::connect_loop(cfg, false)?;
+        if !tracking::exists(&conn, cfg)? {
+            info!(
+                "{}.{} does not exist and would be created",
+                cfg.db_name,
+                cfg.versions_table()
+            );
+            db::finish(conn)?;
+            return Ok(None);
+        }
+        Ok(Some(conn))
+    }
+}
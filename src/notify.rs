@@ -0,0 +1,139 @@
+//! Telling other services how a run went once it is complete.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::http;
+use crate::json;
+use crate::schemata;
+use crate::summary::{Summary, Timing};
+
+/// POST the summary to `url` as JSON.
+pub fn webhook(url: &str, summary: &Summary) -> io::Result<()> {
+    http::send(
+        "POST",
+        url,
+        "application/json",
+        summary.to_json().as_bytes(),
+    )
+}
+
+/// Posts to a Slack incoming webhook when a run changes the database or
+/// fails. Runs with nothing to do are not posted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Slack {
+    pub webhook: String,
+    /// The channel to post to, in place of the webhook's own.
+    pub channel: Option<String>,
+    /// The name of the environment, such as `production`, mentioned in the
+    /// message.
+    pub environment: Option<String>,
+    /// The message, with `${NAME}` placeholders for the `database`,
+    /// `environment`, `command`, `outcome` (`succeeded` or `failed`),
+    /// `icon`, the numbers of files `applied`, `reverted`, `skipped`, and
+    /// `failed`, the `files` applied or reverted, the schema `version`, the
+    /// `duration` in milliseconds, and the `error`.
+    pub template: Option<String>,
+}
+
+impl Slack {
+    pub fn post(&self, summary: &Summary) -> io::Result<()> {
+        if summary.outcome == Ok(0) && summary.failed.is_empty() {
+            return Ok(());
+        }
+        let text = match self.template {
+            Some(ref template) => schemata::substitute(template, &self.vars(summary)),
+            None => self.message(summary),
+        };
+        let mut members = vec![format!("\"text\": {}", json::string(&text))];
+        if let Some(ref channel) = self.channel {
+            members.push(format!("\"channel\": {}", json::string(channel)));
+        }
+        let payload = format!("{{{}}}", members.join(", "));
+        http::send(
+            "POST",
+            &self.webhook,
+            "application/json",
+            payload.as_bytes(),
+        )
+    }
+
+    fn vars(&self, summary: &Summary) -> BTreeMap<String, String> {
+        let files: Vec<&str> = summary
+            .applied
+            .iter()
+            .chain(&summary.reverted)
+            .map(|t| t.path.as_str())
+            .collect();
+        let (outcome, icon, error) = match summary.outcome {
+            Ok(_) => ("succeeded", ":white_check_mark:", ""),
+            Err(ref e) => ("failed", ":x:", e.as_str()),
+        };
+        vec![
+            ("database", summary.database.clone().unwrap_or_default()),
+            ("environment", self.environment.clone().unwrap_or_default()),
+            ("command", summary.command.clone()),
+            ("outcome", outcome.to_string()),
+            ("icon", icon.to_string()),
+            ("applied", summary.applied.len().to_string()),
+            ("reverted", summary.reverted.len().to_string()),
+            ("skipped", summary.skipped.len().to_string()),
+            ("failed", summary.failed.len().to_string()),
+            ("files", files.join(", ")),
+            (
+                "version",
+                summary.version.map(|v| v.to_string()).unwrap_or_default(),
+            ),
+            ("duration", summary.duration.as_millis().to_string()),
+            ("error", error.to_string()),
+            ("labels", labels(summary)),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+    }
+
+    /// The message without a template, such as ":white_check_mark: *app*
+    /// (production): up succeeded, applying 0012_users.sql in 840ms; now at
+    /// version 12".
+    fn message(&self, summary: &Summary) -> String {
+        let vars = self.vars(summary);
+        let mut text = format!("{} *{}*", vars["icon"], vars["database"]);
+        if let Some(ref environment) = self.environment {
+            text += &format!(" ({})", environment);
+        }
+        text += &format!(": {} {}", summary.command, vars["outcome"]);
+        if !summary.applied.is_empty() {
+            text += &format!(", applying {}", join(&summary.applied));
+        }
+        if !summary.reverted.is_empty() {
+            text += &format!(", reverting {}", join(&summary.reverted));
+        }
+        text += &format!(" in {}ms", vars["duration"]);
+        if let Some(version) = summary.version {
+            text += &format!("; now at version {}", version);
+        }
+        if !summary.labels.is_empty() {
+            text += &format!(" [{}]", vars["labels"]);
+        }
+        if let Err(ref error) = summary.outcome {
+            text += &format!("\n```{}```", error);
+        }
+        text
+    }
+}
+
+/// The labels of the run, as in "ticket=OPS-12, pipeline=881".
+fn labels(summary: &Summary) -> String {
+    let labels: Vec<String> = summary
+        .labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    labels.join(", ")
+}
+
+fn join(timings: &[Timing]) -> String {
+    let paths: Vec<&str> = timings.iter().map(|t| t.path.as_str()).collect();
+    paths.join(", ")
+}
@@ -0,0 +1,139 @@
+//! Passwords from a libpq password file, `~/.pgpass` by default.
+//!
+//! Each line is `hostname:port:database:username:password`, in which any of
+//! the first four may be `*` to match anything, and `:` or `\` within a field
+//! is escaped with `\`. The password is the rest of the line after the fourth
+//! unescaped `:`, so it may hold a `:` of its own unescaped. The first line
+//! matching the connection gives the password. As with libpq, `localhost`
+//! matches a Unix socket connection, and a file readable by the group or
+//! others is ignored on Unix.
+
+use std::fs;
+
+/// The password the file at `path` gives for connecting to `database` on
+/// `host` and `port` as `user`, if any.
+pub(crate) fn password(
+    path: &str,
+    host: &str,
+    port: u16,
+    database: &str,
+    user: &str,
+) -> Option<String> {
+    if !private(path) {
+        return None;
+    }
+    find(&fs::read_to_string(path).ok()?, host, port, database, user)
+}
+
+/// The password the first matching line of `text` gives.
+fn find(text: &str, host: &str, port: u16, database: &str, user: &str) -> Option<String> {
+    let host = if host.starts_with('/') {
+        "localhost"
+    } else {
+        host
+    };
+    let port = port.to_string();
+    let wanted = [host, port.as_str(), database, user];
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(fields)
+        .find(|fields| {
+            fields.len() == 5
+                && fields[..4]
+                    .iter()
+                    .zip(&wanted)
+                    .all(|(field, wanted)| field == "*" || field == wanted)
+        })
+        .map(|mut fields| fields.remove(4))
+}
+
+/// The fields of a line, unescaped, splitting off no more than five.
+fn fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.trim_end_matches('\r').chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => fields.last_mut().unwrap().extend(chars.next()),
+            ':' if fields.len() < 5 => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Whether the file is readable only by its owner, warning if it is not, as
+/// libpq does.
+#[cfg(unix)]
+fn private(path: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    match fs::metadata(path) {
+        Ok(meta) if !meta.is_file() => false,
+        Ok(meta) if meta.permissions().mode() & 0o077 != 0 => {
+            warn!(
+                "password file {} has group or world access; permissions should be u=rw (0600) or less",
+                path
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn private(_: &str) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_and_unescapes_fields() {
+        assert_eq!(
+            fields(r"db\:1:5432:*:me:pw"),
+            ["db:1", "5432", "*", "me", "pw"]
+        );
+        assert_eq!(
+            fields(r"h:5432:d:u:a\\b\:c"),
+            ["h", "5432", "d", "u", r"a\b:c"]
+        );
+        assert_eq!(
+            fields("h:5432:d:u:a:b:c\r"),
+            ["h", "5432", "d", "u", "a:b:c"]
+        );
+        assert_eq!(fields("h:5432:d:u"), ["h", "5432", "d", "u"]);
+    }
+
+    #[test]
+    fn takes_the_first_matching_line() {
+        let text = "# comment\n\
+                    other:5432:*:*:nope\n\
+                    db:*:app:deploy:s3:cr:et\n\
+                    *:*:*:*:fallback\n";
+        assert_eq!(
+            find(text, "db", 5432, "app", "deploy").as_deref(),
+            Some("s3:cr:et")
+        );
+        assert_eq!(
+            find(text, "db", 5432, "app", "admin").as_deref(),
+            Some("fallback")
+        );
+        assert_eq!(
+            find("short:5432:app:deploy\n", "short", 5432, "app", "deploy"),
+            None
+        );
+    }
+
+    #[test]
+    fn matches_sockets_as_localhost() {
+        let text = "localhost:5433:*:*:local\n";
+        assert_eq!(
+            find(text, "/var/run/postgresql", 5433, "app", "me").as_deref(),
+            Some("local")
+        );
+        assert_eq!(find(text, "/var/run/postgresql", 5432, "app", "me"), None);
+    }
+}
@@ -0,0 +1,419 @@
+//! Settings read from a `schemato.toml` file.
+//!
+//! The file holds a subset of TOML: keys named for the long command line
+//! options, set to strings, integers, or booleans, optionally grouped into
+//! profiles under `[name]` headers. Keys before the first header apply to
+//! every profile.
+//!
+//! ```toml
+//! schemata = "db/schemata"
+//!
+//! [dev]
+//! database = "app_dev"
+//!
+//! [prod]
+//! host = "db.example.com"
+//! database = "app"
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::schemata::{create_file, create_next, list_sql_files};
+
+/// The name of the file searched for by [`Profiles::find`].
+pub const FILE_NAME: &str = "schemato.toml";
+
+/// The keys a file may set.
+pub const KEYS: &[&str] = &[
+    "database",
+    "url",
+    "schemata",
+    "seeds",
+    "service",
+    "host",
+    "port",
+    "username",
+    "password",
+    "password-file",
+    "password-prompt",
+    "passfile",
+    "vault-addr",
+    "vault-path",
+    "ssh",
+    "ssh-key",
+    "application-name",
+    "attempts",
+    "backoff",
+    "jitter",
+    "max-wait",
+    "target-session-attrs",
+    "wait-for-primary",
+    "no-create-db",
+    "create-role",
+    "grant",
+    "grant-schema",
+    "shadow",
+    "protect-host",
+    "db-owner",
+    "db-encoding",
+    "db-locale",
+    "db-template",
+    "connect-timeout",
+    "server-keepalives-idle",
+    "force",
+    "deadline",
+    "lock-strategy",
+    "no-wait",
+    "lock-timeout-seconds",
+    "lock-key",
+    "allow-modified",
+    "checksum",
+    "require-signatures",
+    "minisign-key",
+    "gpg-keyring",
+    "out-of-order",
+    "notify-channel",
+    "revision",
+    "pre-hook",
+    "post-hook",
+    "tracking-schema",
+    "tracking-table",
+    "track",
+    "statement-timeout",
+    "lock-timeout",
+];
+
+/// The settings file written by [`init`], with the defaults commented out.
+const TEMPLATE: &str = r#"# schemato settings. Options given on the command line or in SCHEMATO_
+# environment variables override the values here.
+
+# Directory containing the schemata, relative to this file.
+schemata = {schemata}
+
+{database}
+# host = "localhost"
+# port = 5432
+# username = "postgres"
+# password = ""
+# attempts = 5
+# backoff = 2
+
+# Profiles, selected with --profile, override the settings above.
+#
+# [dev]
+# database = "app_dev"
+#
+# [prod]
+# host = "db.example.com"
+"#;
+
+/// Keys holding paths, which are relative to the file rather than to the
+/// working directory.
+const PATH_KEYS: &[&str] = &["schemata", "seeds"];
+
+pub type Settings = BTreeMap<String, String>;
+
+/// The contents of a `schemato.toml` file.
+#[derive(Clone, Debug)]
+pub struct Profiles {
+    path: PathBuf,
+    common: Settings,
+    named: BTreeMap<String, Settings>,
+}
+
+impl Profiles {
+    /// Searches the working directory and each of its parents for a
+    /// `schemato.toml`, returning the path of the nearest.
+    pub fn find() -> Option<PathBuf> {
+        let cwd = env::current_dir().ok()?;
+        cwd.ancestors()
+            .map(|dir| dir.join(FILE_NAME))
+            .find(|path| path.is_file())
+    }
+
+    /// Reads and parses the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Profiles> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::Io(format!("failed reading {}", path.display()), e))?;
+        let mut profiles = Profiles {
+            path: path.to_path_buf(),
+            common: Settings::new(),
+            named: BTreeMap::new(),
+        };
+        profiles.parse(&text)?;
+        debug!("loaded settings from {}", path.display());
+        Ok(profiles)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The names of the profiles defined by the file.
+    pub fn names(&self) -> Vec<&str> {
+        self.named.keys().map(String::as_str).collect()
+    }
+
+    /// The settings for `profile`, or only the common settings when no
+    /// profile is given. Relative paths are resolved against the directory
+    /// containing the file.
+    pub fn settings(&self, profile: Option<&str>) -> Result<Settings> {
+        let mut settings = self.common.clone();
+        if let Some(name) = profile {
+            let named = self.named.get(name).ok_or_else(|| {
+                Error::Config(format!("no profile {} in {}", name, self.path.display()))
+            })?;
+            settings.extend(named.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        let dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        for key in PATH_KEYS {
+            if let Some(value) = settings.get_mut(*key) {
+                if Path::new(value.as_str()).is_relative() {
+                    *value = dir.join(value.as_str()).to_string_lossy().into_owned();
+                }
+            }
+        }
+        Ok(settings)
+    }
+
+    fn parse(&mut self, text: &str) -> Result<()> {
+        let path = self.path.display().to_string();
+        let mut section: Option<String> = None;
+        for (n, raw) in text.lines().enumerate() {
+            let bad = |msg: String| Error::Config(format!("{}:{}: {}", path, n + 1, msg));
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                let name = line
+                    .strip_prefix('[')
+                    .and_then(|l| l.strip_suffix(']'))
+                    .map(str::trim)
+                    .filter(|name| is_bare_key(name))
+                    .ok_or_else(|| bad(format!("bad profile header {}", line)))?;
+                if self.named.contains_key(name) {
+                    return Err(bad(format!("profile {} is defined twice", name)));
+                }
+                self.named.insert(name.to_string(), Settings::new());
+                section = Some(name.to_string());
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| bad(format!("expected key = value, found {}", line)))?;
+            let key = key.trim();
+            if !KEYS.contains(&key) {
+                return Err(bad(format!("unknown setting {}", key)));
+            }
+            let value = parse_value(value.trim()).map_err(bad)?;
+            let settings = match section {
+                Some(ref name) => self.named.get_mut(name).unwrap(),
+                None => &mut self.common,
+            };
+            if settings.insert(key.to_string(), value).is_some() {
+                return Err(bad(format!("{} is set twice", key)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A string value with any quotes and backslashes escaped.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn is_bare_key(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Removes a trailing `#` comment, ignoring any `#` inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+/// Parses a string, integer, or boolean value into its text.
+fn parse_value(value: &str) -> std::result::Result<String, String> {
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if chars.as_str().is_empty() => return Ok(out),
+                '"' => break,
+                '\\' => out.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    _ => return Err(format!("unsupported escape in {}", value)),
+                }),
+                c => out.push(c),
+            }
+        }
+        return Err(format!("bad string {}", value));
+    }
+    if let Some(rest) = value.strip_prefix('\'') {
+        return match rest.strip_suffix('\'') {
+            Some(s) if !s.contains('\'') => Ok(s.to_string()),
+            _ => Err(format!("bad string {}", value)),
+        };
+    }
+    if value == "true" || value == "false" {
+        return Ok(value.to_string());
+    }
+    let digits = value.strip_prefix(['+', '-']).unwrap_or(value);
+    if !digits.is_empty()
+        && !digits.starts_with('_')
+        && !digits.ends_with('_')
+        && !digits.contains("__")
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '_')
+    {
+        return Ok(value.trim_start_matches('+').replace('_', ""));
+    }
+    Err(format!("unsupported value {}", value))
+}
+
+/// Start a project in the working directory: write a `schemato.toml` with
+/// commented defaults, create the schemata directory, and create its first
+/// file if it has none. Returns the paths created.
+pub fn init(schemata: &str, database: Option<&str>) -> Result<Vec<String>> {
+    let database = match database {
+        Some(name) => format!("database = {}", quote(name)),
+        None => "# database = \"app\"".to_string(),
+    };
+    let settings = TEMPLATE
+        .replace("{schemata}", &quote(schemata))
+        .replace("{database}", &database);
+    let mut created = vec![create_file(".", FILE_NAME, &settings)?];
+
+    fs::create_dir_all(schemata)
+        .map_err(|e| Error::Io(format!("failed creating {}", schemata), e))?;
+    if list_sql_files(schemata)?.is_empty() {
+        created.extend(create_next(schemata, "initial schema", false, false)?);
+    }
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Profiles> {
+        let mut profiles = Profiles {
+            path: PathBuf::from("/srv/app/schemato.toml"),
+            common: Settings::new(),
+            named: BTreeMap::new(),
+        };
+        profiles.parse(text)?;
+        Ok(profiles)
+    }
+
+    #[test]
+    fn reads_settings_and_profiles() {
+        let profiles = parse(
+            "# settings\n\
+             schemata = \"db/schemata\"   # relative\n\
+             host = 'localhost'\n\
+             attempts = 1_000\n\
+             jitter = true\n\
+             \n\
+             [prod]\n\
+             host = \"db.example.com\"\n\
+             password = \"p#ss \\\"w\\\\rd\\\"\"\n",
+        )
+        .unwrap();
+        assert_eq!(profiles.names(), ["prod"]);
+
+        let common = profiles.settings(None).unwrap();
+        assert_eq!(common["schemata"], "/srv/app/db/schemata");
+        assert_eq!(common["host"], "localhost");
+        assert_eq!(common["attempts"], "1000");
+        assert_eq!(common["jitter"], "true");
+        assert!(!common.contains_key("password"));
+
+        let prod = profiles.settings(Some("prod")).unwrap();
+        assert_eq!(prod["host"], "db.example.com");
+        assert_eq!(prod["password"], "p#ss \"w\\rd\"");
+        assert_eq!(prod["attempts"], "1000");
+        assert!(profiles.settings(Some("staging")).is_err());
+    }
+
+    #[test]
+    fn keeps_absolute_paths() {
+        let profiles = parse("schemata = \"/opt/schemata\"").unwrap();
+        assert_eq!(
+            profiles.settings(None).unwrap()["schemata"],
+            "/opt/schemata"
+        );
+    }
+
+    #[test]
+    fn says_where_files_are_bad() {
+        for &(text, error) in &[
+            (
+                "hots = \"db\"",
+                "/srv/app/schemato.toml:1: unknown setting hots",
+            ),
+            (
+                "\nhost = \"a\"\nhost = \"b\"",
+                "/srv/app/schemato.toml:3: host is set twice",
+            ),
+            (
+                "[dev]\n[dev]",
+                "/srv/app/schemato.toml:2: profile dev is defined twice",
+            ),
+            (
+                "[a b]",
+                "/srv/app/schemato.toml:1: bad profile header [a b]",
+            ),
+            (
+                "host",
+                "/srv/app/schemato.toml:1: expected key = value, found host",
+            ),
+            (
+                "attempts = 1__0",
+                "/srv/app/schemato.toml:1: unsupported value 1__0",
+            ),
+            ("host = \"db", "/srv/app/schemato.toml:1: bad string \"db"),
+            (
+                "host = \"a\\qb\"",
+                "/srv/app/schemato.toml:1: unsupported escape in \"a\\qb\"",
+            ),
+        ] {
+            assert_eq!(parse(text).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn parses_the_file_init_writes() {
+        let text = TEMPLATE
+            .replace("{schemata}", &quote("db/schemata"))
+            .replace("{database}", "database = \"app\"");
+        let settings = parse(&text).unwrap().settings(None).unwrap();
+        assert_eq!(settings["database"], "app");
+        assert_eq!(settings["schemata"], "/srv/app/db/schemata");
+    }
+}
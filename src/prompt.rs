@@ -0,0 +1,46 @@
+//! Asking for a password on the terminal, without echoing it.
+
+use std::io::{self, BufRead, Write};
+
+/// Whether stdin is a terminal, someone being there to answer a prompt.
+#[cfg(unix)]
+pub fn stdin_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stdin_is_terminal() -> bool {
+    false
+}
+
+/// Show `prompt` on stderr and read a line from stdin with echoing turned
+/// off, as psql does.
+#[cfg(unix)]
+pub fn password(prompt: &str) -> io::Result<String> {
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut term) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut quiet = term;
+    quiet.c_lflag &= !(libc::ECHO | libc::ECHONL);
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term) };
+    // The line break typed was not echoed.
+    eprintln!();
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(not(unix))]
+pub fn password(_: &str) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "prompting for a password is only supported on Unix",
+    ))
+}
@@ -0,0 +1,581 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+use chrono::Utc;
+use glob::glob;
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "blake3")]
+use crate::blake3;
+use crate::config::{parse_duration, ChecksumAlgorithm};
+use crate::error::{Error, Result};
+
+/// The name and contents of a schemata file embedded at compile time.
+pub type EmbeddedFile = (&'static str, &'static str);
+
+/// A versioned schemata file and its optional reverse migration.
+#[derive(Clone, Debug)]
+pub struct Migration {
+    pub version: i64,
+    /// The description following the version in a file named like
+    /// `0001_create_users.sql`, with underscores read as spaces.
+    pub description: Option<String>,
+    /// File name of the schemata, relative to the schemata directory.
+    pub path: String,
+    /// File name of the matching `.down.sql` file, if there is one.
+    pub down_path: Option<String>,
+}
+
+/// A file named like `R__views.sql`, applied after the versioned schemata
+/// whenever it has changed since it was last applied. Seed files are
+/// described the same way.
+#[derive(Clone, Debug)]
+pub struct Repeatable {
+    /// The file name without `R__` or `.sql`, with underscores read as
+    /// spaces.
+    pub description: String,
+    /// File name, relative to the schemata directory.
+    pub path: String,
+}
+
+/// Settings given by a `-- schemato:` comment among the comments at the top
+/// of a file, such as `-- schemato: no-transaction, timeout=5m`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Directives {
+    /// Run the file outside of a transaction.
+    pub no_transaction: bool,
+    /// Recorded in place of the description from the file name.
+    pub description: Option<String>,
+    /// Cancel any statement of the file running longer than this.
+    pub timeout: Option<Duration>,
+    /// Fill in the file's `${NAME}` placeholders before it runs.
+    pub substitute: bool,
+}
+
+/// Files run at points in every run that changes the database: before its
+/// first step, before and after each step, and after its last step.
+pub(crate) const CALLBACKS: &[&str] = &[
+    "beforeMigrate.sql",
+    "beforeEach.sql",
+    "afterEach.sql",
+    "afterMigrate.sql",
+];
+
+/// The directive splicing another file, named relative to the schemata
+/// directory, into a file in its place.
+const INCLUDE: &str = "-- schemato:include";
+
+/// The start of the comment giving a file's directives.
+const DIRECTIVES: &str = "-- schemato:";
+
+/// The prefix of the names of repeatable files.
+const REPEATABLE_PREFIX: &str = "R__";
+
+/// Find the schemata files in the directory, sorted by version.
+pub(crate) fn load_schemata(prefix: &str) -> Result<Vec<Migration>> {
+    info!("loading schemata from {}", prefix);
+    parse_names(list_sql_files(prefix)?)
+}
+
+/// The names of the SQL files in a directory.
+pub(crate) fn list_sql_files(prefix: &str) -> Result<Vec<String>> {
+    let pattern = format!("{}/*.sql", prefix);
+    let paths = glob(&pattern)
+        .map_err(|e| Error::Config(format!("bad schemata path {}: {}", prefix, e)))?;
+    let mut names = Vec::new();
+    for g in paths {
+        match g {
+            Ok(ent) => names.push(ent.file_name().unwrap().to_str().unwrap().to_string()),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    Ok(names)
+}
+
+/// The digits of a version numbered by when it was created, such as
+/// `20240601123000`, rather than sequentially.
+const TIMESTAMP_DIGITS: usize = 14;
+
+/// The most digits a version may have, as the largest that fits in a BIGINT.
+const MAX_DIGITS: usize = 19;
+
+/// The version and description of a file named like `0001.sql`,
+/// `0001_create_users.sql`, or `20240601123000_create_users.sql`. The number
+/// may have any width from 1 to 19 digits.
+fn version_of(name: &str) -> Option<(i64, Option<String>)> {
+    let stem = name.strip_suffix(".sql")?;
+    if stem.ends_with(".down") {
+        return None;
+    }
+    let (number, description) = match stem.split_once('_') {
+        Some((number, description)) => (number, Some(description.replace('_', " "))),
+        None => (stem, None),
+    };
+    if (1..=MAX_DIGITS).contains(&number.len()) && number.bytes().all(|b| b.is_ascii_digit()) {
+        Some((number.parse().ok()?, description.filter(|d| !d.is_empty())))
+    } else {
+        None
+    }
+}
+
+/// The file name form of a description: lowercase words joined by
+/// underscores.
+fn slug(description: &str) -> String {
+    description
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Pick the versioned schemata and their down files out of a list of file
+/// names, sorted by version. Fails if more than one file has the same
+/// version.
+pub(crate) fn parse_names(names: Vec<String>) -> Result<Vec<Migration>> {
+    let mut schemata: Vec<Migration> = Vec::new();
+    for f in &names {
+        if let Some((n, description)) = version_of(f) {
+            let down = format!("{}.down.sql", &f[..f.len() - 4]);
+            let has_down = names.contains(&down);
+            schemata.push(Migration {
+                version: n,
+                description,
+                path: f.to_string(),
+                down_path: if has_down { Some(down) } else { None },
+            });
+        }
+    }
+
+    if schemata.is_empty() {
+        warn!("no schemata found");
+    } else {
+        schemata.sort_by(|a, b| (a.version, &a.path).cmp(&(b.version, &b.path)));
+    }
+
+    let mut duplicates = Vec::new();
+    for group in schemata.chunk_by(|a, b| a.version == b.version) {
+        if group.len() > 1 {
+            let paths: Vec<&str> = group.iter().map(|m| m.path.as_str()).collect();
+            duplicates.push(format!("{} ({})", group[0].version, paths.join(", ")));
+        }
+    }
+    if !duplicates.is_empty() {
+        return Err(Error::Inconsistent(format!(
+            "more than one file has version {}",
+            duplicates.join("; ")
+        )));
+    }
+
+    for m in &schemata {
+        match m.down_path {
+            Some(ref down) => info!("found version {} in {} ({})", m.version, m.path, down),
+            None => info!("found version {} in {}", m.version, m.path),
+        }
+    }
+
+    Ok(schemata)
+}
+
+/// Pick the repeatable files out of a list of file names, sorted by name.
+pub(crate) fn parse_repeatables(names: &[String]) -> Vec<Repeatable> {
+    let mut repeatables: Vec<Repeatable> = names
+        .iter()
+        .filter(|f| !f.ends_with(".down.sql"))
+        .filter_map(|f| {
+            let description = f.strip_prefix(REPEATABLE_PREFIX)?.strip_suffix(".sql")?;
+            Some(Repeatable {
+                description: description.replace('_', " "),
+                path: f.to_string(),
+            })
+        })
+        .collect();
+    repeatables.sort_by(|a, b| a.path.cmp(&b.path));
+    for r in &repeatables {
+        info!("found repeatable {}", r.path);
+    }
+    repeatables
+}
+
+/// Pick the callback files out of a list of file names.
+pub(crate) fn parse_callbacks(names: &[String]) -> Vec<String> {
+    let callbacks: Vec<String> = CALLBACKS
+        .iter()
+        .filter(|&&c| names.iter().any(|f| f == c))
+        .map(|c| c.to_string())
+        .collect();
+    for c in &callbacks {
+        info!("found callback {}", c);
+    }
+    callbacks
+}
+
+/// Find the seed files in a directory, sorted by name. A missing directory
+/// has none.
+pub(crate) fn load_seeds(dir: &str) -> Result<Vec<Repeatable>> {
+    if !std::path::Path::new(dir).is_dir() {
+        debug!("no seeds directory {}", dir);
+        return Ok(Vec::new());
+    }
+    debug!("loading seeds from {}", dir);
+    let mut seeds: Vec<Repeatable> = list_sql_files(dir)?
+        .into_iter()
+        .map(|f| Repeatable {
+            description: f.trim_end_matches(".sql").replace('_', " "),
+            path: f,
+        })
+        .collect();
+    seeds.sort_by(|a, b| a.path.cmp(&b.path));
+    for s in &seeds {
+        debug!("found seed {}", s.path);
+    }
+    Ok(seeds)
+}
+
+/// The file named by a line holding an include directive.
+pub(crate) fn include_of(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix(INCLUDE)
+        .filter(|rest| rest.starts_with(char::is_whitespace))
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+}
+
+/// The directives in the comments at the top of a file, before its first
+/// statement. Fails on a directive it does not know.
+pub(crate) fn parse_directives(path: &str, sql: &str) -> Result<Directives> {
+    let mut directives = Directives::default();
+    let mut in_block = false;
+    for line in sql.lines().map(str::trim) {
+        if in_block {
+            in_block = !line.contains("*/");
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("/*") {
+            in_block = !rest.contains("*/");
+            continue;
+        }
+        if !line.is_empty() && !line.starts_with("--") {
+            break;
+        }
+        // Unlike `-- schemato:include`, the directives follow a space.
+        if let Some(items) = line
+            .strip_prefix(DIRECTIVES)
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+        {
+            for item in split_items(items) {
+                directive(path, &item, &mut directives)?;
+            }
+        }
+    }
+    Ok(directives)
+}
+
+/// Set the directive named by one comma-separated `name` or `name=value`
+/// item.
+fn directive(path: &str, item: &str, directives: &mut Directives) -> Result<()> {
+    let (name, value) = match item.find('=') {
+        Some(i) => (item[..i].trim(), Some(unquote(item[i + 1..].trim()))),
+        None => (item.trim(), None),
+    };
+    let bad = |what: &str| Error::Config(format!("{}: {} in directive {}", path, what, item));
+    match (name, value) {
+        ("no-transaction", None) => directives.no_transaction = true,
+        ("substitute", None) => directives.substitute = true,
+        ("description", Some(v)) => directives.description = Some(v),
+        ("timeout", Some(v)) => {
+            directives.timeout = Some(parse_duration(&v).ok_or_else(|| bad("bad duration"))?)
+        }
+        ("no-transaction", Some(_)) | ("substitute", Some(_)) => {
+            return Err(bad("unexpected value"))
+        }
+        ("description", None) | ("timeout", None) => return Err(bad("missing value")),
+        _ => {
+            return Err(Error::Config(format!(
+                "{}: unknown directive {}",
+                path, name
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Split a directive comment on the commas outside of double quotes.
+fn split_items(items: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut item = String::new();
+    let mut quoted = false;
+    let mut chars = items.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quoted => {
+                item.push(c);
+                item.extend(chars.next());
+            }
+            '"' => {
+                quoted = !quoted;
+                item.push(c);
+            }
+            ',' if !quoted => out.push(std::mem::take(&mut item)),
+            _ => item.push(c),
+        }
+    }
+    out.push(item);
+    out.into_iter().filter(|i| !i.trim().is_empty()).collect()
+}
+
+/// A directive value without its surrounding double quotes, if it has
+/// them, and with `\"` and `\\` escapes undone.
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => out.extend(chars.next()),
+                    _ => out.push(c),
+                }
+            }
+            out
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Replace each `${NAME}` placeholder in SQL with the value given for it in
+/// `vars`, leaving placeholders without one as they are. `$${` escapes a
+/// literal `${`.
+pub(crate) fn substitute(sql: &str, vars: &BTreeMap<String, String>) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut rest = sql;
+    while let Some(i) = rest.find("${") {
+        if rest[..i].ends_with('$') {
+            out.push_str(&rest[..i - 1]);
+            out.push_str("${");
+            rest = &rest[i + 2..];
+            continue;
+        }
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 2..];
+        let value = after
+            .find('}')
+            .filter(|&end| is_placeholder(&after[..end]))
+            .and_then(|end| Some((end, vars.get(&after[..end])?)));
+        match value {
+            Some((end, value)) => {
+                out.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push_str("${");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn is_placeholder(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Read a file from the schemata directory.
+pub(crate) fn read(prefix: &str, path: &str) -> Result<String> {
+    std::fs::read_to_string(format!("{}/{}", prefix, path))
+        .map_err(|e| Error::Io(format!("failed reading {}/{}", prefix, path), e))
+}
+
+/// The hex-encoded SHA-256 digest recorded for applied files by default.
+pub fn checksum(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// The checksum recorded for an applied file with `algorithm`: hex-encoded,
+/// and prefixed with the algorithm's name and a colon unless it is SHA-256,
+/// as the checksums recorded before there was a choice are.
+pub fn checksum_with(algorithm: ChecksumAlgorithm, data: &[u8]) -> String {
+    match algorithm {
+        ChecksumAlgorithm::Crc32 => format!("crc32:{:08x}", crc32(data)),
+        ChecksumAlgorithm::Sha256 => checksum(data),
+        #[cfg(feature = "blake3")]
+        ChecksumAlgorithm::Blake3 => {
+            let hash: String = blake3::hash(data)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            format!("blake3:{}", hash)
+        }
+    }
+}
+
+/// The checksum of `data`, read from `path`, computed with the algorithm
+/// of the checksum recorded, for comparing with it.
+pub(crate) fn checksum_like(path: &str, recorded: &str, data: &[u8]) -> Result<String> {
+    let algorithm = match recorded.split_once(':') {
+        None => ChecksumAlgorithm::Sha256,
+        Some((name, _)) => name
+            .parse()
+            .map_err(|e| Error::Config(format!("cannot verify the checksum of {}: {}", path, e)))?,
+    };
+    Ok(checksum_with(algorithm, data))
+}
+
+/// Whether `data`, read from `path`, has the checksum recorded, if there is
+/// one.
+pub(crate) fn checksum_matches(path: &str, recorded: Option<&String>, data: &[u8]) -> Result<bool> {
+    match recorded {
+        Some(recorded) => Ok(&checksum_like(path, recorded, data)? == recorded),
+        None => Ok(false),
+    }
+}
+
+/// The CRC-32 of zlib and PNG.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+/// Create the file for the version following the highest one in the schemata
+/// directory, and optionally its down file. Returns the paths created.
+///
+/// The version is the current UTC time, as `YYYYMMDDHHMMSS`, if `timestamp`
+/// is set or the directory already uses timestamps, and is otherwise the
+/// next number, zero-padded to the width of the latest file's (four digits
+/// in an empty directory).
+pub fn create_next(
+    prefix: &str,
+    description: &str,
+    down: bool,
+    timestamp: bool,
+) -> Result<Vec<String>> {
+    let schemata = load_schemata(prefix)?;
+    let last = schemata.last().map_or(0, |m| m.version);
+    let width = schemata
+        .last()
+        .map_or(4, |m| m.path.bytes().take_while(u8::is_ascii_digit).count());
+    let ver = if timestamp || last.to_string().len() == TIMESTAMP_DIGITS {
+        let now: i64 = Utc::now()
+            .format("%Y%m%d%H%M%S")
+            .to_string()
+            .parse()
+            .unwrap();
+        if now <= last {
+            return Err(Error::Config(format!(
+                "version {} is not after the latest version {}",
+                now, last
+            )));
+        }
+        now
+    } else {
+        last.checked_add(1).ok_or_else(|| {
+            Error::Config(format!("no version follows the latest version {}", last))
+        })?
+    };
+
+    let stem = match slug(description).as_str() {
+        "" => format!("{:0width$}", ver, width = width),
+        slug => format!("{:0width$}_{}", ver, slug, width = width),
+    };
+    let mut created = Vec::new();
+    let up_header = format!("/*\n * Version {}: {}\n */\n\n", ver, description);
+    created.push(create_file(prefix, &format!("{}.sql", stem), &up_header)?);
+    if down {
+        let down_header = format!("/*\n * Revert version {}: {}\n */\n\n", ver, description);
+        created.push(create_file(
+            prefix,
+            &format!("{}.down.sql", stem),
+            &down_header,
+        )?);
+    }
+    Ok(created)
+}
+
+pub(crate) fn create_file(prefix: &str, name: &str, contents: &str) -> Result<String> {
+    let path = format!("{}/{}", prefix, name);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(contents.as_bytes()))
+        .map_err(|e| Error::Io(format!("failed creating {}", path), e))?;
+    info!("created {}", path);
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutes_only_given_placeholders() {
+        let mut vars = BTreeMap::new();
+        vars.insert("reader".to_string(), "app_ro".to_string());
+        assert_eq!(
+            substitute("GRANT SELECT ON t TO ${reader};", &vars),
+            "GRANT SELECT ON t TO app_ro;"
+        );
+        assert_eq!(
+            substitute("SELECT '${HOME}', '$${reader}', '${not a name}'", &vars),
+            "SELECT '${HOME}', '${reader}', '${not a name}'"
+        );
+    }
+
+    #[test]
+    fn substitutes_only_with_the_directive() {
+        let plain = "SELECT 1;";
+        assert!(!parse_directives("0001.sql", plain).unwrap().substitute);
+        let opted = "-- schemato: substitute, timeout=5s\nSELECT '${x}';";
+        assert!(parse_directives("0001.sql", opted).unwrap().substitute);
+        assert!(parse_directives("0001.sql", "-- schemato: substitute=yes\n").is_err());
+    }
+
+    #[test]
+    fn crc32_matches_known_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+    }
+
+    #[test]
+    fn checksums_name_their_algorithm() {
+        assert_eq!(
+            checksum_with(ChecksumAlgorithm::Sha256, b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            checksum_with(ChecksumAlgorithm::Crc32, b"123456789"),
+            "crc32:cbf43926"
+        );
+        #[cfg(feature = "blake3")]
+        assert_eq!(
+            checksum_with(ChecksumAlgorithm::Blake3, b"abc"),
+            "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    #[test]
+    fn verifies_with_the_recorded_algorithm() {
+        let data = b"123456789";
+        assert!(checksum_matches("0001.sql", Some(&"crc32:cbf43926".to_string()), data).unwrap());
+        assert!(!checksum_matches("0001.sql", Some(&"crc32:00000000".to_string()), data).unwrap());
+        assert!(checksum_matches("0001.sql", Some(&checksum(data)), data).unwrap());
+        assert!(!checksum_matches("0001.sql", None, data).unwrap());
+        assert!(checksum_like("0001.sql", "md5:abc", data).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Reporting failed runs to Sentry, with the `sentry` feature.
+//!
+//! Each file that failed becomes an event tagged with the database, the
+//! version, and the file, or if the run failed otherwise, an event for the
+//! run. Errors are sanitized first: the credentials of URIs and the strings
+//! given as a `PASSWORD` are hidden.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+
+use chrono::Utc;
+
+use crate::http;
+use crate::json;
+use crate::logging;
+use crate::summary::Summary;
+
+/// Where events go, from a DSN such as
+/// `https://public@o1.ingest.sentry.io/42`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dsn {
+    key: String,
+    store: String,
+}
+
+impl std::str::FromStr for Dsn {
+    type Err = String;
+
+    fn from_str(dsn: &str) -> std::result::Result<Self, Self::Err> {
+        let bad = || format!("{} is not a Sentry DSN", dsn);
+        let (scheme, rest) = dsn.split_once("://").ok_or_else(bad)?;
+        let (key, rest) = rest.split_once('@').ok_or_else(bad)?;
+        let key = key.split(':').next().unwrap_or_default();
+        let (host, project) = rest.rsplit_once('/').ok_or_else(bad)?;
+        if key.is_empty() || project.is_empty() || !project.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(bad());
+        }
+        // A path before the project, as in host/sentry/42, comes before /api.
+        Ok(Dsn {
+            key: key.to_string(),
+            store: format!("{}://{}/api/{}/store/", scheme, host, project),
+        })
+    }
+}
+
+/// Send an event for each failure of the run, if it failed.
+pub fn report(dsn: &Dsn, environment: Option<&str>, summary: &Summary) -> io::Result<()> {
+    let database = summary.database.as_deref().unwrap_or("");
+    let mut events = Vec::new();
+    for failure in &summary.failed {
+        let mut tags = vec![("file", failure.path.clone())];
+        if let Some(version) = failure.version {
+            tags.push(("version", version.to_string()));
+        }
+        events.push((failure.reason.as_str(), tags));
+    }
+    if let (Err(ref error), true) = (&summary.outcome, summary.failed.is_empty()) {
+        events.push((error.as_str(), Vec::new()));
+    }
+    for (error, mut tags) in events {
+        tags.push(("database", database.to_string()));
+        tags.push(("command", summary.command.clone()));
+        tags.extend(summary.labels.iter().map(|(k, v)| (k.as_str(), v.clone())));
+        send(dsn, &event(environment, &logging::redact(error), &tags))?;
+    }
+    Ok(())
+}
+
+fn event(environment: Option<&str>, error: &str, tags: &[(&str, String)]) -> String {
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|(n, _)| *n == name)
+            .map_or("", |(_, v)| v.as_str())
+    };
+    let tags: Vec<String> = tags
+        .iter()
+        .map(|(k, v)| format!("{}: {}", json::string(k), json::string(v)))
+        .collect();
+    let mut members = vec![
+        ("event_id", json::string(&event_id())),
+        (
+            "timestamp",
+            json::string(&Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+        ),
+        ("platform", json::string("other")),
+        ("level", json::string("error")),
+        ("logger", json::string("schemato")),
+        (
+            "release",
+            json::string(&format!("schemato@{}", env!("CARGO_PKG_VERSION"))),
+        ),
+        (
+            "message",
+            format!("{{\"formatted\": {}}}", json::string(error)),
+        ),
+        ("tags", format!("{{{}}}", tags.join(", "))),
+        // One issue per failing version of a database, however it fails.
+        (
+            "fingerprint",
+            format!(
+                "[{}, {}, {}]",
+                json::string("schemato"),
+                json::string(tag("database")),
+                json::string(match tag("version") {
+                    "" => tag("file"),
+                    version => version,
+                })
+            ),
+        ),
+    ];
+    if let Some(environment) = environment {
+        members.push(("environment", json::string(environment)));
+    }
+    let members: Vec<String> = members
+        .iter()
+        .map(|(k, v)| format!("{}: {}", json::string(k), v))
+        .collect();
+    format!("{{{}}}", members.join(", "))
+}
+
+fn send(dsn: &Dsn, event: &str) -> io::Result<()> {
+    let url = format!(
+        "{}?sentry_version=7&sentry_key={}&sentry_client=schemato/{}",
+        dsn.store,
+        dsn.key,
+        env!("CARGO_PKG_VERSION")
+    );
+    http::send("POST", &url, "application/json", event.as_bytes())
+}
+
+/// 32 random hexadecimal digits.
+fn event_id() -> String {
+    (0..2)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect()
+}
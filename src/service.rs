@@ -0,0 +1,99 @@
+//! Connection settings read from a libpq connection service file.
+//!
+//! The file groups libpq connection parameters under `[name]` headers, one
+//! `keyword=value` per line:
+//!
+//! ```ini
+//! [app-prod]
+//! host=db.example.com
+//! port=5432
+//! dbname=app
+//! user=deploy
+//! ```
+//!
+//! As with libpq, the service is looked for in the file named by
+//! `PGSERVICEFILE`, or else `~/.pg_service.conf`, and then in
+//! `pg_service.conf` within the directory named by `PGSYSCONFDIR`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+
+use crate::error::{Error, Result};
+
+/// The files searched for services, in order.
+pub fn files() -> Vec<String> {
+    let mut files = Vec::new();
+    if let Ok(file) = env::var("PGSERVICEFILE") {
+        files.push(file);
+    } else if let Some(home) = env::var_os("HOME") {
+        files.push(format!("{}/.pg_service.conf", home.to_string_lossy()));
+    }
+    if let Ok(dir) = env::var("PGSYSCONFDIR") {
+        files.push(format!("{}/pg_service.conf", dir));
+    }
+    files
+}
+
+/// The parameters of the service `name`, keyed by their libpq keywords,
+/// from the first of [`files`] that defines it.
+pub fn find(name: &str) -> Result<BTreeMap<String, String>> {
+    for file in files() {
+        let text = match fs::read_to_string(&file) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(Error::Io(format!("failed reading {}", file), e)),
+        };
+        if let Some(service) =
+            parse(&text, name).map_err(|e| Error::Config(format!("{}: {}", file, e)))?
+        {
+            debug!("using service {} from {}", name, file);
+            return Ok(service);
+        }
+    }
+    Err(Error::Config(format!(
+        "definition of service {} not found",
+        name
+    )))
+}
+
+/// The parameters of the service `name` in `text`, if it is defined.
+fn parse(text: &str, name: &str) -> std::result::Result<Option<BTreeMap<String, String>>, String> {
+    let mut service = None;
+    let mut section = None;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            let header = line
+                .strip_prefix('[')
+                .and_then(|l| l.strip_suffix(']'))
+                .ok_or_else(|| format!("line {}: bad section header", number + 1))?;
+            if service.is_some() {
+                break;
+            }
+            section = Some(header.trim());
+            if section == Some(name) {
+                service = Some(BTreeMap::new());
+            }
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected keyword=value", number + 1))?;
+        if section.is_none() {
+            return Err(format!(
+                "line {}: {} outside any service",
+                number + 1,
+                key.trim()
+            ));
+        }
+        if let Some(service) = service.as_mut() {
+            service.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    Ok(service)
+}
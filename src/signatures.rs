@@ -0,0 +1,176 @@
+//! Verifying the detached signatures of schemata files before they run.
+//!
+//! A file signed with minisign has its signature beside it with a `.minisig`
+//! suffix, checked against the public key given by `--minisign-key`. One
+//! signed with GPG has its signature with a `.sig` or `.asc` suffix, checked
+//! against the keyring given by `--gpg-keyring` or the default one. Both
+//! tools must be installed to verify what is signed with them.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+/// Check the signature of the file at `path` in `dir`, read as `content`,
+/// if it has one. Unsigned files are refused if signatures are required.
+/// The content read is what is verified, rather than the file again, so
+/// that it cannot change in between.
+pub(crate) fn verify(cfg: &Config, dir: Option<&str>, path: &str, content: &str) -> Result<()> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return require(cfg, None, path),
+    };
+    let checked = match signature(dir, path) {
+        Some(Signature::Minisign(sig)) => {
+            let key = cfg.minisign_key.as_ref().ok_or_else(|| {
+                Error::Config(format!(
+                    "{} is signed with minisign; give --minisign-key to verify it",
+                    path
+                ))
+            })?;
+            minisign(key, &sig, content)
+        }
+        Some(Signature::Gpg(sig)) => gpg(cfg.gpg_keyring.as_deref(), &sig, content),
+        None if cfg.require_signatures => return Err(unsigned(path)),
+        None => return Ok(()),
+    };
+    let output = checked.map_err(|e| Error::Io(format!("failed verifying {}", path), e))?;
+    if !output.status.success() {
+        // The last line says what was wrong; those before describe the
+        // signature.
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Inconsistent(format!(
+            "{} failed signature verification: {}",
+            path,
+            stderr.trim().lines().last().unwrap_or("")
+        )));
+    }
+    debug!("verified the signature of {}", path);
+    Ok(())
+}
+
+/// Check that the file at `path` in `dir` has a signature, if signatures
+/// are required, so that unsigned files are refused before a run starts.
+pub(crate) fn require(cfg: &Config, dir: Option<&str>, path: &str) -> Result<()> {
+    if !cfg.require_signatures {
+        return Ok(());
+    }
+    match dir {
+        Some(dir) if signature(dir, path).is_some() => Ok(()),
+        Some(_) => Err(unsigned(path)),
+        None => Err(Error::Config(format!(
+            "{} is embedded, and embedded files cannot be verified",
+            path
+        ))),
+    }
+}
+
+enum Signature {
+    Minisign(String),
+    Gpg(String),
+}
+
+/// The signature beside the file, if there is one.
+fn signature(dir: &str, path: &str) -> Option<Signature> {
+    let file = format!("{}/{}", dir, path);
+    let minisig = format!("{}.minisig", file);
+    if Path::new(&minisig).exists() {
+        return Some(Signature::Minisign(minisig));
+    }
+    [".sig", ".asc"]
+        .iter()
+        .map(|suffix| format!("{}{}", file, suffix))
+        .find(|sig| Path::new(sig).exists())
+        .map(Signature::Gpg)
+}
+
+fn unsigned(path: &str) -> Error {
+    Error::Inconsistent(format!(
+        "{} is not signed, and signatures are required",
+        path
+    ))
+}
+
+fn minisign(key: &str, sig: &str, content: &str) -> io::Result<Output> {
+    // minisign only reads what it verifies from a file, so the content is
+    // copied to a new file in a directory only schemato can use, where it
+    // cannot be replaced before minisign reads it.
+    let dir = private_dir()?;
+    let copy = dir.join("schemata.sql");
+    let written = new_file(&copy).and_then(|mut f| f.write_all(content.as_bytes()));
+    let output = written.and_then(|_| {
+        Command::new("minisign")
+            .args(["-V", "-q", "-p", key, "-x", sig, "-m"])
+            .arg(&copy)
+            .output()
+            .map_err(|e| io::Error::new(e.kind(), format!("failed running minisign: {}", e)))
+    });
+    let _ = fs::remove_dir_all(&dir);
+    output
+}
+
+/// A new directory in the temporary directory, readable and writable only
+/// by this user, as `mkdtemp` makes.
+#[cfg(unix)]
+fn private_dir() -> io::Result<PathBuf> {
+    use std::ffi::{CString, OsString};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let template = std::env::temp_dir().join("schemato-XXXXXX");
+    let template = CString::new(template.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut template = template.into_bytes_with_nul();
+    if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+#[cfg(not(unix))]
+fn private_dir() -> io::Result<PathBuf> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "schemato-{}-{}",
+        std::process::id(),
+        DIRS.fetch_add(1, Ordering::Relaxed)
+    ));
+    // Fails rather than reusing a directory that already exists.
+    fs::create_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Create the file, failing if anything, such as a symlink, is already at
+/// `path`.
+fn new_file(path: &Path) -> io::Result<fs::File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+fn gpg(keyring: Option<&str>, sig: &str, content: &str) -> io::Result<Output> {
+    let mut cmd = Command::new("gpg");
+    cmd.args(["--batch", "--no-tty"]);
+    if let Some(keyring) = keyring {
+        cmd.args(["--no-default-keyring", "--keyring", keyring]);
+    }
+    let mut child = cmd
+        .args(["--verify", sig, "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed running gpg: {}", e)))?;
+    child.stdin.take().unwrap().write_all(content.as_bytes())?;
+    child.wait_with_output()
+}
@@ -0,0 +1,335 @@
+//! Splitting SQL files into their statements.
+//!
+//! Semicolons end statements except within comments, quoted strings and
+//! identifiers, dollar-quoted bodies such as `$$ ... $$` or
+//! `$fn$ ... $fn$`, and the `BEGIN ATOMIC ... END` bodies of SQL-standard
+//! functions.
+//!
+//! [`redact`] hides the passwords of statements echoed to the log.
+
+/// One statement of a SQL file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Statement<'a> {
+    /// The statement's text, without any comments before it, through its
+    /// terminating semicolon if it has one.
+    pub sql: &'a str,
+    /// The line of the file on which the statement starts, counting from 1.
+    pub line: usize,
+}
+
+impl<'a> Statement<'a> {
+    /// The line of the file holding the character at `position`, counting
+    /// from 1, as Postgres reports the position of an error.
+    pub fn line_at(&self, position: usize) -> usize {
+        let before = self.sql.chars().take(position.saturating_sub(1));
+        self.line + before.filter(|&c| c == '\n').count()
+    }
+
+    /// The lines of the statement near `line` of the file, numbered, with
+    /// that line marked.
+    pub fn excerpt(&self, line: usize) -> String {
+        let lines = self
+            .sql
+            .lines()
+            .enumerate()
+            .map(|(i, text)| (self.line + i, text));
+        lines
+            .filter(|&(n, _)| n + EXCERPT_LINES >= line && n <= line + EXCERPT_LINES)
+            .map(|(n, text)| {
+                let mark = if n == line { '>' } else { ' ' };
+                format!("{} {:>5} | {}", mark, n, text)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// How many lines either side of a failure an excerpt shows.
+const EXCERPT_LINES: usize = 3;
+
+/// The statements of a SQL file, in order. Comments and whitespace between
+/// statements are dropped.
+pub(crate) fn split(sql: &str) -> Vec<Statement<'_>> {
+    let bytes = sql.as_bytes();
+    let mut statements = Vec::new();
+    // Offset and line of the first significant byte of the current statement.
+    let mut start: Option<(usize, usize)> = None;
+    let mut line = 1;
+    // The previous word of the statement, for finding BEGIN ATOMIC bodies.
+    let mut prev_word = String::new();
+    let mut atomic = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if b == b'\n' {
+            line += 1;
+            i += 1;
+            continue;
+        }
+        if b.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        if b == b'-' && bytes.get(i + 1) == Some(&b'-') {
+            i = find(bytes, i, b"\n").unwrap_or(bytes.len());
+            continue;
+        }
+        if b == b'/' && bytes.get(i + 1) == Some(&b'*') {
+            let end = block_comment_end(bytes, i);
+            line += count_lines(&bytes[i..end]);
+            i = end;
+            continue;
+        }
+
+        if start.is_none() {
+            start = Some((i, line));
+        }
+        let end = match b {
+            b';' if atomic == 0 => {
+                let (offset, first) = start.take().unwrap();
+                statements.push(Statement {
+                    sql: &sql[offset..=i],
+                    line: first,
+                });
+                prev_word.clear();
+                i + 1
+            }
+            b'\'' => {
+                let escapes = i > 0 && matches!(bytes[i - 1], b'E' | b'e') && !ident(bytes, i - 1);
+                quoted_end(bytes, i, b'\'', escapes)
+            }
+            b'"' => quoted_end(bytes, i, b'"', false),
+            b'$' => match dollar_tag(bytes, i) {
+                Some(tag) => find(bytes, i + tag.len(), tag)
+                    .map(|end| end + tag.len())
+                    .unwrap_or(bytes.len()),
+                None => i + 1,
+            },
+            _ if is_ident_start(b) && !ident(bytes, i) => {
+                let mut end = i + 1;
+                while end < bytes.len() && is_ident_char(bytes[end]) {
+                    end += 1;
+                }
+                let word = sql[i..end].to_ascii_uppercase();
+                let opens = word == "ATOMIC" && prev_word == "BEGIN";
+                if opens || (atomic > 0 && word == "CASE") {
+                    atomic += 1;
+                } else if atomic > 0 && word == "END" {
+                    atomic -= 1;
+                }
+                prev_word = word;
+                end
+            }
+            _ => i + 1,
+        };
+        line += count_lines(&bytes[i..end]);
+        i = end;
+    }
+    if let Some((offset, first)) = start {
+        statements.push(Statement {
+            sql: sql[offset..].trim_end(),
+            line: first,
+        });
+    }
+    statements
+}
+
+/// The statement with the string following each `PASSWORD` keyword, as in
+/// `CREATE ROLE app PASSWORD 'secret'`, replaced with `'********'`.
+pub(crate) fn redact(sql: &str) -> String {
+    let bytes = sql.as_bytes();
+    let mut out = String::with_capacity(sql.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let word = i + 8 <= bytes.len()
+            && bytes[i..i + 8].eq_ignore_ascii_case(b"password")
+            && !ident(bytes, i)
+            && !bytes.get(i + 8).is_some_and(|&b| is_ident_char(b));
+        if !word {
+            i += 1;
+            continue;
+        }
+        let mut j = i + 8;
+        while j < bytes.len() && bytes[j].is_ascii_whitespace() {
+            j += 1;
+        }
+        let escapes = j < bytes.len() && (bytes[j] == b'E' || bytes[j] == b'e');
+        let quote = if escapes { j + 1 } else { j };
+        if bytes.get(quote) == Some(&b'\'') {
+            let end = quoted_end(bytes, quote, b'\'', escapes);
+            out.push_str(&sql[copied..j]);
+            out.push_str("'********'");
+            copied = end;
+            i = end;
+        } else {
+            i = j;
+        }
+    }
+    out.push_str(&sql[copied..]);
+    out
+}
+
+/// The offset of the next `needle` at or after `from`.
+fn find(bytes: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    bytes[from..]
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|p| from + p)
+}
+
+fn count_lines(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&b| b == b'\n').count()
+}
+
+/// The offset following the block comment starting at `from`. Block
+/// comments nest.
+fn block_comment_end(bytes: &[u8], from: usize) -> usize {
+    let mut depth = 0;
+    let mut i = from;
+    while i + 1 < bytes.len() {
+        match (bytes[i], bytes[i + 1]) {
+            (b'/', b'*') => {
+                depth += 1;
+                i += 2;
+            }
+            (b'*', b'/') => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// The offset following the string or identifier quoted by `quote` starting
+/// at `from`. A doubled quote stands for itself, as does a quote following a
+/// backslash in an escape string such as `E'it\'s'`.
+fn quoted_end(bytes: &[u8], from: usize, quote: u8, escapes: bool) -> usize {
+    let mut i = from + 1;
+    while i < bytes.len() {
+        if escapes && bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// The dollar-quote tag, such as `$$` or `$body$`, starting at `from`, if
+/// there is one there rather than a parameter such as `$1`.
+fn dollar_tag(bytes: &[u8], from: usize) -> Option<&[u8]> {
+    if ident(bytes, from) {
+        return None;
+    }
+    let mut i = from + 1;
+    if i < bytes.len() && is_ident_start(bytes[i]) {
+        i += 1;
+        while i < bytes.len() && is_ident_char(bytes[i]) && bytes[i] != b'$' {
+            i += 1;
+        }
+    }
+    if bytes.get(i) == Some(&b'$') {
+        Some(&bytes[from..=i])
+    } else {
+        None
+    }
+}
+
+/// Whether the byte at `at` continues an identifier or number begun before
+/// it.
+fn ident(bytes: &[u8], at: usize) -> bool {
+    at > 0 && is_ident_char(bytes[at - 1])
+}
+
+fn is_ident_start(b: u8) -> bool {
+    b.is_ascii_alphabetic() || b == b'_' || b >= 0x80
+}
+
+fn is_ident_char(b: u8) -> bool {
+    is_ident_start(b) || b.is_ascii_digit() || b == b'$'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text and starting line of each statement.
+    fn texts(sql: &str) -> Vec<(&str, usize)> {
+        split(sql).into_iter().map(|s| (s.sql, s.line)).collect()
+    }
+
+    #[test]
+    fn splits_on_semicolons_and_drops_comments() {
+        let sql = "-- users\nCREATE TABLE users (id int);\n\n/* a\n/* nested */ comment */\nINSERT INTO users VALUES (1);\nSELECT 1";
+        assert_eq!(
+            texts(sql),
+            [
+                ("CREATE TABLE users (id int);", 2),
+                ("INSERT INTO users VALUES (1);", 6),
+                ("SELECT 1", 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_semicolons_in_strings_and_identifiers() {
+        let sql = "SELECT 'a;''b', E'c\\';d', \"e;f\";\nSELECT 2;";
+        assert_eq!(
+            texts(sql),
+            [("SELECT 'a;''b', E'c\\';d', \"e;f\";", 1), ("SELECT 2;", 2)]
+        );
+    }
+
+    #[test]
+    fn keeps_dollar_quoted_bodies_whole() {
+        let sql = "CREATE FUNCTION f() RETURNS int AS $fn$\nBEGIN\n  RETURN 1;\nEND;\n$fn$ LANGUAGE plpgsql;\nPREPARE p AS SELECT $1;";
+        let statements = texts(sql);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].0.ends_with("$fn$ LANGUAGE plpgsql;"));
+        assert_eq!(statements[1], ("PREPARE p AS SELECT $1;", 6));
+    }
+
+    #[test]
+    fn keeps_begin_atomic_bodies_whole() {
+        let sql = "CREATE FUNCTION f(x int) RETURNS int BEGIN ATOMIC\n  SELECT CASE WHEN x > 0 THEN 1 END;\n  SELECT 2;\nEND;\nSELECT 3;";
+        let statements = texts(sql);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].0.ends_with("SELECT 2;\nEND;"));
+        assert_eq!(statements[1], ("SELECT 3;", 5));
+    }
+
+    #[test]
+    fn finds_the_line_of_a_position() {
+        let statement = &split("\n\nSELECT 1,\n  oops;")[0];
+        assert_eq!(statement.line_at(1), 3);
+        assert_eq!(statement.line_at(14), 4);
+    }
+
+    #[test]
+    fn redacts_password_strings() {
+        assert_eq!(
+            redact("CREATE ROLE app LOGIN PASSWORD 'it''s' VALID UNTIL 'infinity';"),
+            "CREATE ROLE app LOGIN PASSWORD '********' VALID UNTIL 'infinity';"
+        );
+        assert_eq!(
+            redact("ALTER ROLE app password E'a\\'b';"),
+            "ALTER ROLE app password '********';"
+        );
+        assert_eq!(
+            redact("UPDATE t SET password_hash = 'x', old_password = 'y';"),
+            "UPDATE t SET password_hash = 'x', old_password = 'y';"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! A summary of each run, gathered from its [events](crate::events) for
+//! reporting once the run is complete.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::events::{self, Event};
+use crate::json;
+use crate::logging;
+
+/// How long a file took to apply or revert.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timing {
+    pub version: Option<i64>,
+    pub path: String,
+    pub duration: Duration,
+}
+
+/// A file left out of a run, or one that failed, with why.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Omission {
+    pub version: Option<i64>,
+    pub path: String,
+    pub reason: String,
+}
+
+/// What a command did.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Summary {
+    pub command: String,
+    pub database: Option<String>,
+    /// The number of files applied or reverted, or the error the command
+    /// failed with.
+    pub outcome: std::result::Result<usize, String>,
+    pub duration: Duration,
+    pub applied: Vec<Timing>,
+    pub reverted: Vec<Timing>,
+    pub skipped: Vec<Omission>,
+    pub failed: Vec<Omission>,
+    /// The latest version installed once the run was done, if it got that
+    /// far.
+    pub version: Option<i64>,
+    /// The labels given to the run.
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Summary {
+    fn new() -> Summary {
+        Summary {
+            command: String::new(),
+            database: None,
+            outcome: Ok(0),
+            duration: Duration::default(),
+            applied: Vec::new(),
+            reverted: Vec::new(),
+            skipped: Vec::new(),
+            failed: Vec::new(),
+            version: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, event: &Event) {
+        match *event {
+            Event::Applied {
+                version,
+                ref path,
+                duration,
+            } => self.applied.push(Timing {
+                version,
+                path: path.clone(),
+                duration,
+            }),
+            Event::Reverted {
+                version,
+                ref path,
+                duration,
+            } => self.reverted.push(Timing {
+                version: Some(version),
+                path: path.clone(),
+                duration,
+            }),
+            Event::Skipped {
+                version,
+                ref path,
+                ref reason,
+            } => self.skipped.push(Omission {
+                version,
+                path: path.clone(),
+                reason: reason.clone(),
+            }),
+            Event::Failed {
+                version,
+                ref path,
+                ref error,
+            } => self.failed.push(Omission {
+                version,
+                path: path.clone(),
+                reason: error.clone(),
+            }),
+            Event::Installed { version } => self.version = version,
+            Event::Complete {
+                ref command,
+                ref outcome,
+                duration,
+                ref labels,
+            } => {
+                self.command = command.clone();
+                self.labels = labels.clone();
+                self.database = logging::current().database;
+                self.outcome = outcome.clone();
+                self.duration = duration;
+            }
+            _ => {}
+        }
+    }
+
+    /// The lines of the summary as text.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} {}: {} applied, {} reverted, {} skipped, {} failed in {}ms",
+            self.command,
+            match self.outcome {
+                Ok(_) => "succeeded",
+                Err(_) => "failed",
+            },
+            self.applied.len(),
+            self.reverted.len(),
+            self.skipped.len(),
+            self.failed.len(),
+            self.duration.as_millis()
+        )];
+        for (verb, timings) in &[("applied", &self.applied), ("reverted", &self.reverted)] {
+            for t in timings.iter() {
+                lines.push(format!(
+                    "  {} {} in {}ms",
+                    verb,
+                    t.path,
+                    t.duration.as_millis()
+                ));
+            }
+        }
+        for (verb, omissions) in &[("skipped", &self.skipped), ("failed", &self.failed)] {
+            for o in omissions.iter() {
+                lines.push(format!("  {} {}: {}", verb, o.path, o.reason));
+            }
+        }
+        match self.version {
+            Some(v) => lines.push(format!("  schema version {}", v)),
+            None if self.outcome.is_ok() => lines.push("  no version installed".to_string()),
+            None => {}
+        }
+        lines
+    }
+
+    /// The summary as a JSON object.
+    pub fn to_json(&self) -> String {
+        let version = |v: Option<i64>| v.map_or("null".to_string(), |v| v.to_string());
+        let timings = |ts: &[Timing]| {
+            let ts: Vec<String> = ts
+                .iter()
+                .map(|t| {
+                    format!(
+                        "{{\"version\": {}, \"path\": {}, \"duration_ms\": {}}}",
+                        version(t.version),
+                        json::string(&t.path),
+                        t.duration.as_millis()
+                    )
+                })
+                .collect();
+            format!("[{}]", ts.join(", "))
+        };
+        let omissions = |os: &[Omission], field: &str| {
+            let os: Vec<String> = os
+                .iter()
+                .map(|o| {
+                    format!(
+                        "{{\"version\": {}, \"path\": {}, {}: {}}}",
+                        version(o.version),
+                        json::string(&o.path),
+                        json::string(field),
+                        json::string(&o.reason)
+                    )
+                })
+                .collect();
+            format!("[{}]", os.join(", "))
+        };
+        let mut members = vec![("command", json::string(&self.command))];
+        if let Some(ref database) = self.database {
+            members.push(("database", json::string(database)));
+        }
+        match self.outcome {
+            Ok(changed) => {
+                members.push(("outcome", json::string("success")));
+                members.push(("changed", changed.to_string()));
+            }
+            Err(ref error) => {
+                members.push(("outcome", json::string("failure")));
+                members.push(("error", json::string(error)));
+            }
+        }
+        members.push(("duration_ms", self.duration.as_millis().to_string()));
+        members.push(("schema_version", version(self.version)));
+        members.push(("labels", json::object(&self.labels)));
+        members.push(("applied", timings(&self.applied)));
+        members.push(("reverted", timings(&self.reverted)));
+        members.push(("skipped", omissions(&self.skipped, "reason")));
+        members.push(("failed", omissions(&self.failed, "error")));
+        let members: Vec<String> = members
+            .iter()
+            .map(|(k, v)| format!("  {}: {}", json::string(k), v))
+            .collect();
+        format!("{{\n{}\n}}\n", members.join(",\n"))
+    }
+}
+
+/// Call `f` with the summary of every command that completes from now on.
+pub fn on_complete<F: Fn(&Summary) + Send + 'static>(f: F) {
+    let summary = Mutex::new(Summary::new());
+    events::listen(move |event| {
+        let mut summary = summary.lock().unwrap();
+        summary.record(event);
+        if let Event::Complete { .. } = *event {
+            f(&summary);
+            *summary = Summary::new();
+        }
+    });
+}
@@ -0,0 +1,489 @@
+//! schemato's own migrations of the tables tracking installed versions.
+//!
+//! Each format of the tracking tables is reached from the previous one by a
+//! change in [`FORMATS`], and the format of a database is recorded in
+//! `schemato.format`. Opening a database for writing applies whichever
+//! changes it lacks, so databases managed by older versions of schemato are
+//! upgraded in place.
+//!
+//! The statements name the tracking objects with placeholders filled in by
+//! [`names`], so that they can be moved with `Config::tracking_schema` and
+//! `Config::tracking_table`.
+
+use std::collections::BTreeSet;
+
+use postgres::GenericConnection;
+
+use crate::config::{Config, LockStrategy};
+use crate::db;
+use crate::error::{Error, Result};
+
+/// The changes bringing the schema from each format to the next: change `n`
+/// produces format `n + 1`. Changes are only ever appended.
+const FORMATS: &[&str] = &[
+    // 1: the versions table, with version 0 recording its creation.
+    r#"
+        CREATE SCHEMA IF NOT EXISTS {schema};
+
+        CREATE TABLE {versions} (
+            version INTEGER NOT NULL PRIMARY KEY,
+            tstamp  TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        );
+
+        INSERT INTO {versions} (version) VALUES (0);
+    "#,
+    // 2: checksums of applied files.
+    r#"
+        ALTER TABLE {versions} ADD COLUMN checksum TEXT;
+    "#,
+    // 3: file name, duration, user, host, and outcome of each version.
+    r#"
+        ALTER TABLE {versions}
+            ADD COLUMN filename TEXT,
+            ADD COLUMN duration_ms BIGINT,
+            ADD COLUMN applied_by TEXT,
+            ADD COLUMN client_host TEXT,
+            ADD COLUMN success BOOLEAN NOT NULL DEFAULT true,
+            ALTER COLUMN applied_by SET DEFAULT current_user;
+    "#,
+    // 4: the format itself, which earlier formats are recognized without.
+    r#"
+        CREATE TABLE {format} (version INTEGER NOT NULL);
+        INSERT INTO {format} (version) VALUES (0);
+    "#,
+    // 5: tracks, each with its own sequence of versions.
+    r#"
+        ALTER TABLE {versions}
+            ADD COLUMN track TEXT NOT NULL DEFAULT 'default',
+            DROP CONSTRAINT {table}_pkey,
+            ADD PRIMARY KEY (track, version);
+    "#,
+    // 6: descriptions taken from file names.
+    r#"
+        ALTER TABLE {versions} ADD COLUMN description TEXT;
+    "#,
+    // 7: versions wide enough for timestamps.
+    r#"
+        ALTER TABLE {versions} ALTER COLUMN version TYPE BIGINT;
+    "#,
+    // 8: versions applied after a later version.
+    r#"
+        ALTER TABLE {versions}
+            ADD COLUMN out_of_order BOOLEAN NOT NULL DEFAULT false;
+    "#,
+    // 9: the last applied checksum of each repeatable file.
+    r#"
+        CREATE TABLE {repeatables} (
+            track       TEXT NOT NULL,
+            filename    TEXT NOT NULL,
+            checksum    TEXT NOT NULL,
+            tstamp      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+            duration_ms BIGINT,
+            applied_by  TEXT DEFAULT current_user,
+            client_host TEXT,
+            PRIMARY KEY (track, filename)
+        );
+    "#,
+    // 10: the last applied checksum of each seed file.
+    r#"
+        CREATE TABLE {seeds} (
+            track       TEXT NOT NULL,
+            filename    TEXT NOT NULL,
+            checksum    TEXT NOT NULL,
+            tstamp      TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+            duration_ms BIGINT,
+            applied_by  TEXT DEFAULT current_user,
+            client_host TEXT,
+            PRIMARY KEY (track, filename)
+        );
+    "#,
+    // 11: every run that could change the database, kept append-only.
+    r#"
+        CREATE TABLE {runs} (
+            id          BIGSERIAL PRIMARY KEY,
+            track       TEXT NOT NULL,
+            command     TEXT NOT NULL,
+            started     TIMESTAMP WITH TIME ZONE NOT NULL,
+            finished    TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+            run_by      TEXT NOT NULL DEFAULT current_user,
+            client_host TEXT,
+            outcome     TEXT NOT NULL,
+            error       TEXT,
+            changed     BIGINT,
+            applied     TEXT[] NOT NULL,
+            reverted    TEXT[] NOT NULL,
+            versions    BIGINT[] NOT NULL,
+            tool_version TEXT NOT NULL
+        );
+
+        CREATE FUNCTION {runs}_append_only() RETURNS trigger
+        LANGUAGE plpgsql AS $$
+        BEGIN
+            RAISE EXCEPTION '{runs} is append-only';
+        END
+        $$;
+
+        CREATE TRIGGER append_only
+            BEFORE UPDATE OR DELETE ON {runs}
+            FOR EACH ROW EXECUTE PROCEDURE {runs}_append_only();
+        CREATE TRIGGER append_only_truncate
+            BEFORE TRUNCATE ON {runs}
+            FOR EACH STATEMENT EXECUTE PROCEDURE {runs}_append_only();
+    "#,
+    // 12: the revision of the schemata each file and run came from.
+    r#"
+        ALTER TABLE {versions} ADD COLUMN revision TEXT;
+        ALTER TABLE {repeatables} ADD COLUMN revision TEXT;
+        ALTER TABLE {seeds} ADD COLUMN revision TEXT;
+        ALTER TABLE {runs} ADD COLUMN revision TEXT;
+    "#,
+    // 13: labels given to each version's run and each run.
+    r#"
+        ALTER TABLE {versions} ADD COLUMN labels JSONB;
+        ALTER TABLE {runs} ADD COLUMN labels JSONB;
+    "#,
+];
+
+/// The first format recorded in the format table.
+const RECORDED: usize = 4;
+
+/// Columns of the versions table missing from older formats, and the values
+/// read in their place until the schema is upgraded.
+pub(crate) const OPTIONAL_COLUMNS: &[(&str, &str)] = &[
+    ("checksum", "NULL::text"),
+    ("filename", "NULL::text"),
+    ("duration_ms", "NULL::bigint"),
+    ("applied_by", "NULL::text"),
+    ("client_host", "NULL::text"),
+    ("success", "true"),
+    ("description", "NULL::text"),
+    ("out_of_order", "false"),
+    ("revision", "NULL::text"),
+];
+
+/// Fill in the names of the tracking objects in a statement: `{schema}`,
+/// the unqualified `{table}`, and the schema-qualified `{versions}`,
+/// `{format}`, `{repeatables}`, `{seeds}`, and `{runs}` tables.
+pub(crate) fn names(cfg: &Config, sql: &str) -> String {
+    sql.replace("{schema}", &cfg.tracking_schema)
+        .replace("{table}", &cfg.tracking_table)
+        .replace("{versions}", &cfg.versions_table())
+        .replace("{format}", &cfg.format_table())
+        .replace("{repeatables}", &cfg.repeatables_table())
+        .replace("{seeds}", &cfg.seeds_table())
+        .replace("{runs}", &cfg.runs_table())
+        .replace("{lock}", &cfg.lock_table())
+}
+
+/// The keywords Postgres reserves, which cannot name a schema or table
+/// unquoted, in sorted order.
+const RESERVED: &[&str] = &[
+    "all",
+    "analyse",
+    "analyze",
+    "and",
+    "any",
+    "array",
+    "as",
+    "asc",
+    "asymmetric",
+    "authorization",
+    "binary",
+    "both",
+    "case",
+    "cast",
+    "check",
+    "collate",
+    "collation",
+    "column",
+    "concurrently",
+    "constraint",
+    "create",
+    "cross",
+    "current_catalog",
+    "current_date",
+    "current_role",
+    "current_schema",
+    "current_time",
+    "current_timestamp",
+    "current_user",
+    "default",
+    "deferrable",
+    "desc",
+    "distinct",
+    "do",
+    "else",
+    "end",
+    "except",
+    "false",
+    "fetch",
+    "for",
+    "foreign",
+    "freeze",
+    "from",
+    "full",
+    "grant",
+    "group",
+    "having",
+    "ilike",
+    "in",
+    "initially",
+    "inner",
+    "intersect",
+    "into",
+    "is",
+    "isnull",
+    "join",
+    "lateral",
+    "leading",
+    "left",
+    "like",
+    "limit",
+    "localtime",
+    "localtimestamp",
+    "natural",
+    "not",
+    "notnull",
+    "null",
+    "offset",
+    "on",
+    "only",
+    "or",
+    "order",
+    "outer",
+    "overlaps",
+    "placing",
+    "primary",
+    "references",
+    "returning",
+    "right",
+    "select",
+    "session_user",
+    "similar",
+    "some",
+    "symmetric",
+    "system_user",
+    "table",
+    "tablesample",
+    "then",
+    "to",
+    "trailing",
+    "true",
+    "union",
+    "unique",
+    "user",
+    "using",
+    "variadic",
+    "verbose",
+    "when",
+    "where",
+    "window",
+    "with",
+];
+
+/// Check that the tracking names can be used in statements unquoted.
+pub(crate) fn check_names(cfg: &Config) -> Result<()> {
+    for name in &[&cfg.tracking_schema, &cfg.tracking_table] {
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+            && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid {
+            return Err(Error::Config(format!(
+                "bad tracking name {}: use lowercase letters, digits, and underscores",
+                name
+            )));
+        }
+        if RESERVED.binary_search(&name.as_str()).is_ok() {
+            return Err(Error::Config(format!(
+                "bad tracking name {}: it is a reserved word",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a table, named with its schema, exists. The catalog is read
+/// rather than its cache, which may miss tables created while waiting for a
+/// lock taken in the transaction.
+pub(crate) fn table_exists(conn: &dyn GenericConnection, table: &str) -> postgres::Result<bool> {
+    let query_for_table = r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM pg_catalog.pg_class c
+            JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+            WHERE n.nspname || '.' || c.relname = $1
+        )
+    "#;
+    Ok(conn.query(query_for_table, &[&table])?.get(0).get(0))
+}
+
+/// Whether the versions table exists.
+pub(crate) fn exists(conn: &dyn GenericConnection, cfg: &Config) -> Result<bool> {
+    table_exists(conn, &cfg.versions_table()).map_err(|e| {
+        Error::Sql(
+            format!(
+                "failed to determine existence of {}.{}",
+                cfg.db_name,
+                cfg.versions_table()
+            ),
+            e,
+        )
+    })
+}
+
+/// The columns of the versions table.
+pub(crate) fn columns(conn: &dyn GenericConnection, cfg: &Config) -> Result<BTreeSet<String>> {
+    let query_for_columns = r#"
+        SELECT column_name::text
+        FROM information_schema.columns
+        WHERE table_schema = $1
+        AND table_name = $2
+    "#;
+
+    let rows = conn
+        .query(
+            query_for_columns,
+            &[&cfg.tracking_schema, &cfg.tracking_table],
+        )
+        .map_err(|e| Error::Sql(format!("failed inspecting {}", cfg.versions_table()), e))?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Create the tracking tables, or upgrade them to the current format. When
+/// the lock is taken in each transaction, this is done in one, after taking
+/// it and checking again what is there.
+pub(crate) fn upgrade(conn: &dyn GenericConnection, cfg: &Config, exists: bool) -> Result<()> {
+    if cfg.lock_strategy != LockStrategy::Transaction {
+        return upgrade_with(conn, cfg, exists);
+    }
+    let err = |e| Error::Sql(format!("failed upgrading {}", cfg.versions_table()), e);
+    let t = conn.transaction().map_err(err)?;
+    db::xact_lock(&t, cfg)?;
+    let exists = self::exists(&t, cfg)?;
+    upgrade_with(&t, cfg, exists)?;
+    t.commit().map_err(err)
+}
+
+fn upgrade_with(conn: &dyn GenericConnection, cfg: &Config, exists: bool) -> Result<()> {
+    let tables = format!("{}.{}", cfg.db_name, cfg.versions_table());
+    let current = if exists { format(conn, cfg)? } else { 0 };
+    if current > FORMATS.len() {
+        return Err(Error::Inconsistent(format!(
+            "{} has format {}, which is newer than this version of schemato supports ({})",
+            tables,
+            current,
+            FORMATS.len()
+        )));
+    }
+
+    if !exists {
+        info!("creating {}", tables);
+    }
+    for (n, change) in FORMATS.iter().enumerate().skip(current) {
+        let format = n as i32 + 1;
+        if exists {
+            info!("upgrading {} to format {}", tables, format);
+        } else {
+            debug!("creating format {} of {}", format, tables);
+        }
+        let err = |e| {
+            Error::Sql(
+                format!("failed upgrading {} to format {}", tables, format),
+                e,
+            )
+        };
+        let t = conn.transaction().map_err(err)?;
+        t.batch_execute(&names(cfg, change)).map_err(err)?;
+        if n + 1 >= RECORDED {
+            t.execute(&names(cfg, "UPDATE {format} SET version = $1"), &[&format])
+                .map_err(err)?;
+        }
+        t.commit().map_err(err)?;
+    }
+    Ok(())
+}
+
+/// Record version 0 of the configured track if it has not been used before,
+/// as the creation of the versions table is for the default track.
+pub(crate) fn start_track(conn: &dyn GenericConnection, cfg: &Config) -> Result<()> {
+    let started = conn
+        .execute(
+            &names(
+                cfg,
+                "INSERT INTO {versions} (track, version) VALUES ($1, 0) ON CONFLICT DO NOTHING",
+            ),
+            &[&cfg.track],
+        )
+        .map_err(|e| Error::Sql(format!("failed starting track {}", cfg.track), e))?;
+    if started > 0 {
+        info!("starting track {}", cfg.track);
+    }
+    Ok(())
+}
+
+/// The format of existing tracking tables. Formats from before it was
+/// recorded are recognized by their columns.
+fn format(conn: &dyn GenericConnection, cfg: &Config) -> Result<usize> {
+    let err = |e| Error::Sql(format!("failed reading {}", cfg.format_table()), e);
+    if table_exists(conn, &cfg.format_table()).map_err(err)? {
+        let rows = conn
+            .query(&names(cfg, "SELECT version FROM {format}"), &[])
+            .map_err(err)?;
+        return match rows.iter().next() {
+            Some(row) => Ok(row.get::<_, i32>(0) as usize),
+            None => Err(Error::Inconsistent(format!(
+                "{} has no version",
+                cfg.format_table()
+            ))),
+        };
+    }
+
+    let columns = columns(conn, cfg)?;
+    Ok(if columns.contains("success") {
+        3
+    } else if columns.contains("checksum") {
+        2
+    } else {
+        1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(schema: &str, table: &str) -> Result<()> {
+        let mut cfg = Config::new("app");
+        cfg.tracking_schema = schema.to_string();
+        cfg.tracking_table = table.to_string();
+        check_names(&cfg)
+    }
+
+    #[test]
+    fn reserved_is_sorted() {
+        assert!(RESERVED.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn accepts_plain_names() {
+        assert!(check("schemato", "versions").is_ok());
+        assert!(check("_ops2", "user_versions").is_ok());
+    }
+
+    #[test]
+    fn rejects_names_needing_quotes() {
+        for &(schema, table) in &[
+            ("user", "versions"),
+            ("schemato", "order"),
+            ("Schemato", "versions"),
+            ("schemato", "2versions"),
+            ("schemato", "ver-sions"),
+            ("", "versions"),
+        ] {
+            assert!(check(schema, table).is_err(), "{}.{}", schema, table);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! SSH tunnels through a jump host to servers not reachable directly.
+//!
+//! Each tunnel is an `ssh -N -L` process forwarding a local port to the
+//! server, authenticating with the key given by `--ssh-key` or whatever
+//! `ssh` would use itself: the agent, `~/.ssh/config`, and the default keys.
+//! Tunnels are shared by the connections to the same server and closed when
+//! schemato exits.
+
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+/// How long to wait for `ssh` to start forwarding.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Tunnel {
+    host: String,
+    port: u16,
+    local: u16,
+    ssh: Child,
+}
+
+static TUNNELS: Mutex<Vec<Tunnel>> = Mutex::new(Vec::new());
+
+/// The local port forwarded to `port` on `host` through `cfg.ssh`, opening
+/// the tunnel if it is not already open. A `host` beginning with a slash is
+/// the directory of the server's Unix socket on the jump host.
+pub(crate) fn forward(cfg: &Config, host: &str, port: u16) -> Result<u16> {
+    let jump = cfg.ssh.as_deref().unwrap_or_default();
+    let mut tunnels = TUNNELS.lock().unwrap();
+    tunnels.retain_mut(|t| matches!(t.ssh.try_wait(), Ok(None)));
+    if let Some(tunnel) = tunnels.iter().find(|t| t.host == host && t.port == port) {
+        return Ok(tunnel.local);
+    }
+    #[cfg(unix)]
+    {
+        static CLOSE: Once = Once::new();
+        CLOSE.call_once(|| unsafe {
+            libc::atexit(close);
+        });
+    }
+
+    let local = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|l| l.local_addr())
+        .map_err(|e| Error::Io("failed finding a free port to tunnel".to_string(), e))?
+        .port();
+    let target = if host.starts_with('/') {
+        format!("{}/.s.PGSQL.{}", host, port)
+    } else if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let (destination, ssh_port) = match jump.rsplit_once(':') {
+        Some((destination, p)) if p.parse::<u16>().is_ok() => (destination, Some(p)),
+        _ => (jump, None),
+    };
+    let mut cmd = Command::new("ssh");
+    cmd.args([
+        "-N",
+        "-o",
+        "ExitOnForwardFailure=yes",
+        "-o",
+        "BatchMode=yes",
+    ]);
+    if let Some(p) = ssh_port {
+        cmd.args(["-p", p]);
+    }
+    if let Some(ref key) = cfg.ssh_key {
+        cmd.args(["-i", key]);
+    }
+    let mut ssh = cmd
+        .arg("-L")
+        .arg(format!("127.0.0.1:{}:{}", local, target))
+        .arg("--")
+        .arg(destination)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Io("failed running ssh".to_string(), e))?;
+
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, local));
+    let start = Instant::now();
+    loop {
+        if let Ok(Some(status)) = ssh.try_wait() {
+            let mut stderr = String::new();
+            let _ = ssh.stderr.take().unwrap().read_to_string(&mut stderr);
+            return Err(Error::Connect(format!(
+                "SSH tunnel through {} failed ({}): {}",
+                jump,
+                status,
+                stderr.trim().lines().last().unwrap_or("")
+            )));
+        }
+        if TcpStream::connect_timeout(&addr, Duration::from_millis(200)).is_ok() {
+            break;
+        }
+        if start.elapsed() > TIMEOUT {
+            let _ = ssh.kill();
+            return Err(Error::Connect(format!(
+                "SSH tunnel through {} did not open within {}s",
+                jump,
+                TIMEOUT.as_secs()
+            )));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    info!("tunneling to {} through {} on port {}", target, jump, local);
+    tunnels.push(Tunnel {
+        host: host.to_string(),
+        port,
+        local,
+        ssh,
+    });
+    Ok(local)
+}
+
+/// Close the tunnels as schemato exits.
+#[cfg(unix)]
+extern "C" fn close() {
+    if let Ok(mut tunnels) = TUNNELS.try_lock() {
+        for tunnel in tunnels.iter_mut() {
+            let _ = tunnel.ssh.kill();
+            let _ = tunnel.ssh.wait();
+        }
+    }
+}
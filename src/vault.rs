@@ -0,0 +1,149 @@
+//! Database credentials fetched from HashiCorp Vault.
+//!
+//! The path is read with the token from `VAULT_TOKEN` or `~/.vault-token`,
+//! as the `vault` command does. It may be a database secrets engine's
+//! `creds/ROLE` or `static-creds/ROLE`, or a KV secret holding `username`
+//! and `password`. Dynamic credentials come with a lease, which is renewed
+//! in the background for as long as schemato runs.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::http;
+use crate::json::{self, Value};
+
+/// A username and password read from Vault.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    lease: Option<Lease>,
+}
+
+#[derive(Clone, Debug)]
+struct Lease {
+    id: String,
+    duration: Duration,
+}
+
+/// The token from `VAULT_TOKEN`, or else `~/.vault-token`.
+pub fn token() -> Option<String> {
+    if let Ok(token) = env::var("VAULT_TOKEN") {
+        return Some(token);
+    }
+    let home = env::var_os("HOME")?;
+    let token = fs::read_to_string(format!("{}/.vault-token", home.to_string_lossy())).ok()?;
+    Some(token.trim().to_string())
+}
+
+/// Read the credentials at `path` from the Vault server at `addr`.
+pub fn credentials(addr: &str, token: &str, path: &str) -> Result<Credentials> {
+    let url = format!(
+        "{}/v1/{}",
+        addr.trim_end_matches('/'),
+        path.trim_matches('/')
+    );
+    let response = http::request(
+        "GET",
+        &url,
+        &[("X-Vault-Token", token)],
+        "application/json",
+        b"",
+    )
+    .map_err(|e| Error::Io(format!("failed reading {} from Vault", path), e))?;
+    let secret = json::parse(&response)
+        .map_err(|e| Error::Config(format!("Vault's response for {} is not JSON: {}", path, e)))?;
+    let data = secret.get("data");
+    // KV version 2 nests the secret within its metadata.
+    let data = data.and_then(|d| d.get("data")).or(data);
+    let field = |name: &str| {
+        data.and_then(|d| d.get(name))
+            .and_then(Value::as_str)
+            .map(String::from)
+            .ok_or_else(|| Error::Config(format!("{} in Vault has no {}", path, name)))
+    };
+    let credentials = Credentials {
+        username: field("username")?,
+        password: field("password")?,
+        lease: lease(&secret),
+    };
+    match credentials.lease {
+        Some(ref lease) => info!(
+            "using credentials of {} from Vault, leased for {}s",
+            credentials.username,
+            lease.duration.as_secs()
+        ),
+        None => info!("using credentials of {} from Vault", credentials.username),
+    }
+    Ok(credentials)
+}
+
+/// The renewable lease of a secret or renewal.
+fn lease(secret: &Value) -> Option<Lease> {
+    let id = secret.get("lease_id").and_then(Value::as_str)?;
+    let duration = secret.get("lease_duration").and_then(Value::as_f64)?;
+    let renewable = secret.get("renewable") == Some(&Value::Bool(true));
+    if id.is_empty() || !renewable || duration <= 0.0 {
+        return None;
+    }
+    Some(Lease {
+        id: id.to_string(),
+        duration: Duration::from_secs(duration as u64),
+    })
+}
+
+impl Credentials {
+    /// Renew the credentials' lease, if they have one, each time two thirds
+    /// of it has passed, until the process exits or Vault stops renewing it.
+    pub fn keep_renewed(&self, addr: &str, token: &str) {
+        let mut lease = match self.lease.clone() {
+            Some(lease) => lease,
+            None => return,
+        };
+        let url = format!("{}/v1/sys/leases/renew", addr.trim_end_matches('/'));
+        let token = token.to_string();
+        thread::spawn(move || loop {
+            thread::sleep(lease.duration * 2 / 3);
+            match renew(&url, &token, &lease) {
+                Ok(Some(renewed)) => {
+                    debug!("renewed Vault lease for {}s", renewed.duration.as_secs());
+                    lease = renewed;
+                }
+                Ok(None) => {
+                    warn!("Vault will not renew the lease of the database credentials further");
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        "failed renewing the lease of the database credentials: {}",
+                        e
+                    );
+                    return;
+                }
+            }
+        });
+    }
+}
+
+fn renew(url: &str, token: &str, current: &Lease) -> io::Result<Option<Lease>> {
+    let body = format!(
+        "{{\"lease_id\": {}, \"increment\": {}}}",
+        json::string(&current.id),
+        current.duration.as_secs()
+    );
+    let response = http::request(
+        "PUT",
+        url,
+        &[("X-Vault-Token", token)],
+        "application/json",
+        body.as_bytes(),
+    )?;
+    let renewed =
+        json::parse(&response).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    // A lease renewed to no longer than is left is at its maximum TTL.
+    Ok(lease(&renewed).filter(|renewed| renewed.duration >= current.duration / 3))
+}